@@ -0,0 +1,216 @@
+//! Backends that generate chat completions
+//!
+//! - `ChatBackend`: The trait every chat completion provider implements
+//! - `ChatRequest`: A provider independent chat completion request
+//! - `OpenAiBackend`: Sends requests to the OpenAI API or any OpenAI compatible API
+//! - `DryRunBackend`: Echoes the prompt back without sending anything, for `DRY_RUN`
+//!
+
+use std::sync::Arc;
+
+use serde_json::json;
+use serenity::async_trait;
+
+use crate::structures::*;
+use crate::utils::parse_api_response;
+
+/// The response of a chat backend
+///
+/// Backends for other providers convert their responses into the OpenAI format.
+pub type ChatResponse = ApiResponseStruct;
+
+/// A chat completion request, independent of the provider it is sent to.
+///
+/// ### Fields
+///
+/// * `model` - The model to use for the completion.
+/// * `messages` - The system prompt, chat history and the new prompt.
+/// * `max_tokens` - The maximum number of tokens to generate.
+/// * `temperature` - The temperature to use for the completion.
+/// * `top_p` - The probability mass of the tokens to sample from.
+/// * `n` - The number of completion choices to generate.
+/// * `user` - The user ID of the user making the request.
+/// * `tools` - The tools the model may call.
+/// * `tool_choice` - Controls whether the model calls a tool, e.g. `auto` or `none`.
+/// * `json_mode` - Whether the model has to reply with a JSON object.
+/// * `seed` - The seed for reproducible replies, if any.
+/// * `presence_penalty` - Penalizes the tokens that are already in the text.
+/// * `frequency_penalty` - Penalizes the tokens by how often they are already in the text.
+///
+#[derive(Clone, Debug, Default)]
+pub struct ChatRequest {
+	pub model: String,
+	pub messages: Vec<Message>,
+	pub max_tokens: u32,
+	pub temperature: f32,
+	pub top_p: f32,
+	pub n: u32,
+	pub user: String,
+	pub tools: Vec<ToolDefinition>,
+	pub tool_choice: Option<String>,
+	pub json_mode: bool,
+	pub seed: Option<u64>,
+	pub presence_penalty: f32,
+	pub frequency_penalty: f32,
+}
+
+/// A provider of chat completions
+#[async_trait]
+pub trait ChatBackend {
+	/// Generates a completion for the request.
+	///
+	/// ### Errors
+	///
+	/// Returns a `BotError` if the request could not be sent or the provider returned an error.
+	async fn complete(&self, req: ChatRequest) -> Result<ChatResponse, BotError>;
+}
+
+/// Sends chat requests to the `chat/completions` endpoint of the configured API
+pub struct OpenAiBackend {
+	config: Arc<ConfigStruct>,
+	client: reqwest::Client,
+}
+impl OpenAiBackend {
+	pub fn new(config: Arc<ConfigStruct>, client: reqwest::Client) -> Self {
+		Self { config, client }
+	}
+}
+#[async_trait]
+impl ChatBackend for OpenAiBackend {
+	async fn complete(&self, req: ChatRequest) -> Result<ChatResponse, BotError> {
+		// the API rejects a tool choice without any tools
+		let (tools, tool_choice) = if req.tools.is_empty() {
+			(None, None)
+		} else {
+			(Some(req.tools), req.tool_choice)
+		};
+		let params = ApiRequestBody {
+			model: req.model,
+			messages: req.messages,
+			max_tokens: req.max_tokens,
+			temperature: req.temperature,
+			top_p: req.top_p,
+			n: req.n,
+			user: req.user,
+			tools,
+			tool_choice,
+			response_format: req.json_mode.then(|| ResponseFormat {
+				format_type: "json_object".to_string(),
+			}),
+			seed: req.seed,
+			presence_penalty: req.presence_penalty,
+			frequency_penalty: req.frequency_penalty,
+		};
+
+		let url = self.config.api_url("chat/completions");
+		let body = json!(params).to_string();
+		// the API key is only sent in the headers of the client, so it is never part of the body
+		if self.config.log_prompts {
+			debug!("Request to {}: {}", url, body);
+		}
+		let res = self.client.post(url).body(body).send().await?;
+		let response = parse_api_response::<ApiResponseStruct>(res).await?;
+		if self.config.log_prompts {
+			debug!("Response: {:?}", response);
+		}
+		Ok(response)
+	}
+}
+
+/// Answers every request by echoing its prompt, without sending anything or using any tokens
+///
+/// Used with `DRY_RUN`, so the commands, chat history and privacy settings can be tried without an
+/// API key.
+pub struct DryRunBackend;
+#[async_trait]
+impl ChatBackend for DryRunBackend {
+	async fn complete(&self, req: ChatRequest) -> Result<ChatResponse, BotError> {
+		let prompt = req
+			.messages
+			.iter()
+			.rev()
+			.find(|message| message.role == "user")
+			.map(|message| message.content.to_string())
+			.unwrap_or_default();
+		let choices = (0..req.n.max(1))
+			.map(|index| ChoiceStruct {
+				index,
+				finish_reason: "stop".to_string(),
+				message: Message::new("assistant", format!("[dry run] {}", prompt)),
+				logprobs: None,
+			})
+			.collect();
+		Ok(ApiResponseStruct {
+			id: "dry-run".to_string(),
+			object: "chat.completion".to_string(),
+			created: chrono::Utc::now().timestamp() as u64,
+			choices,
+			usage: UsageStruct {
+				prompt_tokens: 0,
+				completion_tokens: 0,
+				total_tokens: 0,
+			},
+			system_fingerprint: None,
+			cached: false,
+			fallback_model: None,
+		})
+	}
+}
+
+/// A backend returning canned responses, for tests
+///
+/// Every request it receives is recorded, and the responses are returned in the order they were queued.
+#[cfg(test)]
+pub struct MockBackend {
+	responses: std::sync::Mutex<std::collections::VecDeque<Result<ChatResponse, BotError>>>,
+	requests: std::sync::Mutex<Vec<ChatRequest>>,
+}
+#[cfg(test)]
+impl MockBackend {
+	pub fn new(responses: Vec<ChatResponse>) -> Self {
+		Self::with_results(responses.into_iter().map(Ok).collect())
+	}
+
+	/// Queues errors as well as responses, to test how failed requests are handled.
+	pub fn with_results(results: Vec<Result<ChatResponse, BotError>>) -> Self {
+		Self {
+			responses: std::sync::Mutex::new(results.into()),
+			requests: std::sync::Mutex::new(Vec::new()),
+		}
+	}
+
+	pub fn requests(&self) -> Vec<ChatRequest> {
+		self.requests.lock().unwrap().clone()
+	}
+
+	/// Builds a response with a single message and the given token usage.
+	pub fn response(message: Message, prompt_tokens: u32, completion_tokens: u32) -> ChatResponse {
+		ApiResponseStruct {
+			id: "mock".to_string(),
+			object: "chat.completion".to_string(),
+			created: 0,
+			choices: vec![ChoiceStruct {
+				index: 0,
+				finish_reason: if message.tool_calls.is_some() { "tool_calls" } else { "stop" }.to_string(),
+				message,
+				logprobs: None,
+			}],
+			usage: UsageStruct {
+				prompt_tokens,
+				completion_tokens,
+				total_tokens: prompt_tokens + completion_tokens,
+			},
+			system_fingerprint: None,
+			cached: false,
+			fallback_model: None,
+		}
+	}
+}
+#[cfg(test)]
+#[async_trait]
+impl ChatBackend for MockBackend {
+	async fn complete(&self, req: ChatRequest) -> Result<ChatResponse, BotError> {
+		self.requests.lock().unwrap().push(req);
+		self.responses.lock().unwrap().pop_front().expect("no response queued in MockBackend")
+	}
+}
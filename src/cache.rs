@@ -0,0 +1,158 @@
+//! A cache of the AI responses to one-off prompts
+//!
+//! - `ResponseCacheKey`: What a cached response is looked up by
+//! - `ResponseCache`: Keeps the latest responses for a while, evicting the least recently used
+//!
+//! Only prompts sent without the chat history are cached, like `/ask`. The chat history of
+//! `/chat` changes with every exchange, so the same prompt is never sent twice there.
+//!
+
+use std::time::{Duration, Instant};
+
+use rustc_hash::FxHashMap;
+
+use crate::structures::ApiResponseStruct;
+
+/// What a cached response is looked up by
+///
+/// ### Fields
+///
+/// * `model` - The model the prompt was sent to.
+/// * `system_prompt` - The system prompt of the personality, as it was sent.
+/// * `prompt` - The prompt of the user.
+/// * `seed` - The seed of the user, if any.
+///
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ResponseCacheKey {
+	pub model: String,
+	pub system_prompt: String,
+	pub prompt: String,
+	pub seed: Option<u64>,
+}
+
+/// A cached response, with when it was stored and last used
+#[derive(Clone, Debug)]
+struct CachedResponse {
+	stored: Instant,
+	last_used: Instant,
+	response: ApiResponseStruct,
+}
+
+/// Keeps the latest responses for `ttl`, at most `capacity` of them
+///
+/// When it is full, the least recently used response is evicted to make room for a new one.
+///
+/// ### Methods
+///
+/// * `get` - Returns the response stored for a key, if it hasn't expired
+/// * `insert` - Stores the response for a key
+///
+#[derive(Clone, Debug)]
+pub struct ResponseCache {
+	ttl: Duration,
+	capacity: usize,
+	entries: FxHashMap<ResponseCacheKey, CachedResponse>,
+}
+impl ResponseCache {
+	pub fn new(ttl: Duration, capacity: usize) -> Self {
+		Self {
+			ttl,
+			capacity,
+			entries: FxHashMap::default(),
+		}
+	}
+
+	/// Returns whether responses are cached at all, they aren't without a TTL or capacity.
+	pub fn is_enabled(&self) -> bool {
+		!self.ttl.is_zero() && self.capacity > 0
+	}
+
+	/// Returns the response stored for the key, if it hasn't expired at `now`.
+	pub fn get(&mut self, key: &ResponseCacheKey, now: Instant) -> Option<ApiResponseStruct> {
+		let ttl = self.ttl;
+		self.entries.retain(|_, cached| now.duration_since(cached.stored) < ttl);
+		let cached = self.entries.get_mut(key)?;
+		cached.last_used = now;
+		Some(cached.response.clone())
+	}
+
+	/// Stores the response for the key at `now`, evicting the least recently used one when full.
+	pub fn insert(&mut self, key: ResponseCacheKey, response: ApiResponseStruct, now: Instant) {
+		if !self.is_enabled() {
+			return;
+		}
+		if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+			let least_recently_used = self
+				.entries
+				.iter()
+				.min_by_key(|(_, cached)| cached.last_used)
+				.map(|(key, _)| key.clone());
+			if let Some(least_recently_used) = least_recently_used {
+				self.entries.remove(&least_recently_used);
+			}
+		}
+		self.entries.insert(
+			key,
+			CachedResponse {
+				stored: now,
+				last_used: now,
+				response,
+			},
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::MockBackend;
+	use crate::structures::Message;
+
+	fn key(prompt: &str) -> ResponseCacheKey {
+		ResponseCacheKey {
+			model: "gpt-3.5-turbo".to_string(),
+			system_prompt: "You are a helpful assistant.".to_string(),
+			prompt: prompt.to_string(),
+			seed: None,
+		}
+	}
+
+	fn response(message: &str) -> ApiResponseStruct {
+		MockBackend::response(Message::new("assistant", message.to_string()), 10, 5)
+	}
+
+	#[test]
+	fn responses_expire_after_the_ttl() {
+		let mut cache = ResponseCache::new(Duration::from_secs(60), 10);
+		let now = Instant::now();
+		cache.insert(key("Capital of France?"), response("Paris"), now);
+
+		let cached = cache.get(&key("Capital of France?"), now + Duration::from_secs(30));
+		assert_eq!(cached.unwrap().choices[0].message.content, "Paris");
+		assert!(cache.get(&key("Capital of Spain?"), now).is_none());
+		assert!(cache.get(&key("Capital of France?"), now + Duration::from_secs(60)).is_none());
+	}
+
+	#[test]
+	fn the_least_recently_used_response_is_evicted() {
+		let mut cache = ResponseCache::new(Duration::from_secs(60), 2);
+		let now = Instant::now();
+		cache.insert(key("a"), response("A"), now);
+		cache.insert(key("b"), response("B"), now + Duration::from_secs(1));
+		cache.get(&key("a"), now + Duration::from_secs(2));
+		cache.insert(key("c"), response("C"), now + Duration::from_secs(3));
+
+		let later = now + Duration::from_secs(4);
+		assert!(cache.get(&key("a"), later).is_some());
+		assert!(cache.get(&key("b"), later).is_none());
+		assert!(cache.get(&key("c"), later).is_some());
+	}
+
+	#[test]
+	fn nothing_is_cached_without_a_ttl() {
+		let mut cache = ResponseCache::new(Duration::ZERO, 10);
+		let now = Instant::now();
+		cache.insert(key("a"), response("A"), now);
+		assert!(cache.get(&key("a"), now).is_none());
+	}
+}
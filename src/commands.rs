@@ -1,13 +1,27 @@
+use serde_json::json;
 use serenity::{
+  builder::CreateComponents,
   client::Context,
-  model::application::interaction::application_command::ApplicationCommandInteraction,
+  model::{
+    application::{
+      component::{ActionRowComponent, ButtonStyle, InputTextStyle},
+      interaction::{
+        application_command::{ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue},
+        autocomplete::AutocompleteInteraction,
+        message_component::MessageComponentInteraction, modal::ModalSubmitInteraction,
+        InteractionResponseType,
+      },
+    },
+    channel::{MessageFlags, Reaction},
+    id::UserId,
+  },
 };
+use std::collections::HashSet;
 
 use crate::utils::*;
 use crate::{
-  handlers::HandlerStruct,
-  structures::{ApiResponse, Choice, Usage},
-  users::{Personality, UserChatHistoryEntry},
+  handlers::{HandlerStruct, PendingChoices, TrackedReply},
+  users::{estimate_tokens, Model, Personality, ResponseLength, DEFAULT_SESSION},
 };
 
 /// Handles the `/chat` command
@@ -24,330 +38,2487 @@ pub async fn chat_command(
   handler: &HandlerStruct,
   ctx: &Context,
   command: &ApplicationCommandInteraction,
-) {
+) -> Option<CommandResponse> {
   let prompt = command
     .data
     .options
-    .get(0)
+    .iter()
+    .find(|opt| opt.name == "chat")
     .and_then(|opt| opt.value.as_ref())
     .and_then(|value| value.as_str())
     .unwrap_or("");
+  let attachment = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "file")
+    .and_then(|opt| match &opt.resolved {
+      Some(CommandDataOptionValue::Attachment(attachment)) => Some(attachment),
+      _ => None,
+    });
+  let in_thread = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "in_thread")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_bool())
+    .unwrap_or(false);
+  // Discord limits thread names to 100 characters
+  let thread_name: String = match prompt.trim() {
+    "" => "Chat".to_string(),
+    prompt => prompt.chars().take(100).collect(),
+  };
 
   let user_id = command.user.id;
-  let channel_id = command.channel_id;
-  let user_channel_key = (user_id, channel_id);
   let user_name = command.user.name.clone();
-  // if the user is not in the map, add them
+  let tag = command_tag(command);
   // log the user's prompt
   info!(
-    "User {}#{}: {}",
-    user_name, command.user.discriminator, prompt
+    "{} User {}#{}: {}",
+    tag, user_name, command.user.discriminator, prompt
   );
+  handler.get_metrics().chat_requests.inc();
+
+  // the attached file has its own size limit, so only the typed message is checked
+  if let Err(message) = check_prompt_length(&handler.get_config(), prompt) {
+    info!(
+      "{} Refused prompt from {}#{}: {}",
+      tag, user_name, command.user.discriminator, message
+    );
+    return Some(CommandResponse::private(message));
+  }
+
+  // shown above a reply posted through a webhook, without the attached file
+  let typed_prompt = prompt;
+  // an image is looked at by the AI, the contents of other files go before the message so the
+  // message can refer to them
+  let mut image_urls = Vec::new();
+  let prompt = match attachment {
+    Some(attachment) if is_image_attachment(attachment.content_type.as_deref()) => {
+      info!("{} Attached image: {} ({} bytes)", tag, attachment.filename, attachment.size);
+      let model = handler
+        .with_user(user_id, |user| user.with_settings(|settings| settings.get_model().clone()))
+        .unwrap_or_default();
+      if let Err(message) = check_image_attachment(&model, attachment.size) {
+        return Some(CommandResponse::private(message));
+      }
+      image_urls.push(attachment.url.clone());
+      prompt.to_string()
+    }
+    Some(attachment) => {
+      info!("{} Attached file: {} ({} bytes)", tag, attachment.filename, attachment.size);
+      match download_text_attachment(handler, attachment).await {
+        Ok(contents) => format!(
+          "Contents of {}:\n```\n{}\n```\n\n{}",
+          attachment.filename, contents, prompt
+        ),
+        Err(message) => {
+          return Some(CommandResponse::private(message));
+        }
+      }
+    }
+    None => prompt.to_string(),
+  };
+  let prompt = prompt.as_str();
+
+  if let Err(message) = check_chat_allowed(handler, user_id, prompt).await {
+    info!(
+      "{} Refused prompt from {}#{}: {}",
+      tag, user_name, command.user.discriminator, message
+    );
+    return Some(CommandResponse::private(message));
+  }
+
+  let chat_privacy = handler
+    .with_user(user_id, |user| user.with_settings(|settings| settings.chat_privacy))
+    .unwrap();
+  // the thread starts from the public reply, there is none for private replies or in DMs
+  let thread_id = if in_thread && !chat_privacy && command.guild_id.is_some() {
+    create_chat_thread(ctx, command, &thread_name).await
+  } else {
+    None
+  };
+  // the conversation continues in the thread, so its history is kept under the thread
+  let channel_id = thread_id.unwrap_or(command.channel_id);
+  let user_channel_key = (user_id, channel_id);
 
   // Generate the AI response and handle any errors
-  let response = match generate_ai_response(handler, prompt, user_channel_key).await {
+  let typing = start_typing(ctx.http.clone(), channel_id);
+  let response = generate_ai_response_with_images(handler, prompt, &image_urls, user_channel_key).await;
+  drop(typing);
+  let response = match response {
+    Ok(response) => response,
+    Err(e) => {
+      error!("{} Error generating response: {}", tag, e);
+      return Some(CommandResponse::generation_error(&e));
+    }
+  };
+  // with several replies the user picks one before anything is stored in the history
+  if response.choices.len() > 1 {
+    let content = choices_message(&response);
+    let choice_count = response.choices.len();
+    match command
+      .edit_original_interaction_response(&ctx.http, |message| {
+        message.content(content).allowed_mentions(no_mentions).components(|components| {
+          components.create_action_row(|row| {
+            row.create_select_menu(|menu| {
+              menu
+                .custom_id("choice")
+                .placeholder("Pick the reply to keep")
+                .options(|options| {
+                  for index in 0..choice_count {
+                    options.create_option(|option| {
+                      option
+                        .label(format!("Reply {}", index + 1))
+                        .value(index.to_string())
+                    });
+                  }
+                  options
+                })
+            })
+          })
+        })
+      })
+      .await
+    {
+      Ok(message) => handler.add_pending_choices(
+        message.id,
+        PendingChoices {
+          user_id,
+          channel_id,
+          prompt: prompt.to_string(),
+          response,
+        },
+      ),
+      Err(why) => error!("{} Error sending the replies to pick from: {:?}", tag, why),
+    }
+    return None;
+  }
+
+  let message = reply_message(handler, user_id, &response);
+
+  let sent_in_thread = match thread_id {
+    Some(thread_id) => send_message(ctx, thread_id, &message, None).await.is_ok(),
+    None => false,
+  };
+  // webhooks only post in the channel itself, and their messages are public
+  let use_webhook = handler.get_config().use_webhook
+    && !chat_privacy
+    && command.guild_id.is_some()
+    && thread_id.is_none();
+  let sent_by_webhook = use_webhook && reply_with_webhook(handler, ctx, command, typed_prompt, &message).await.is_ok();
+  // fall back to the channel of the command when the thread was archived or deleted meanwhile
+  let (channel_id, reply_message_ids) = if sent_in_thread || sent_by_webhook {
+    (channel_id, Vec::new())
+  } else {
+    if thread_id.is_some() {
+      warn!("{} Could not reply in the thread, replying in the channel instead", tag);
+    }
+    let Ok(reply_message_ids) =
+      edit_original_message_or_create_followup(handler, ctx, command, message, &chat_privacy).await
+    else {
+      return None;
+    };
+    (command.channel_id, reply_message_ids)
+  };
+  if let Err(warning) = check_json_reply(handler, user_id, &response) {
+    if let Err(err) = create_followup_message(ctx, command, warning, &true).await {
+      error!("{} Error sending follow-up message: {:?}", tag, err);
+    }
+  }
+
+  record_chat_history(handler, user_id, channel_id, prompt, &response);
+  // only public replies of the bot itself can be edited later and have reactions, not a thread or webhook message
+  if let Some(&message_id) = reply_message_ids.first().filter(|_| !chat_privacy) {
+    record_reply_messages(handler, user_id, channel_id, reply_message_ids);
+    add_reaction_controls(handler, ctx, channel_id, message_id, user_id).await;
+  }
+  None
+}
+
+/// Handles the `/regenerate` command
+///
+/// Removes the latest response in the channel and generates a new one for the same prompt.
+/// A public reply is edited in place, see `record_reply_messages`, otherwise the new one is posted
+/// as the response to the command.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn regenerate_command(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let user_id = command.user.id;
+  let channel_id = command.channel_id;
+  let tag = command_tag(command);
+
+  // in a shared channel only the user's own latest exchange can be regenerated
+  let last_entry = handler.modify_channel_data(user_id, channel_id, |channel_data| {
+    let own_entry = channel_data
+      .chat_history
+      .last()
+      .is_some_and(|entry| entry.author.is_none() || entry.author == Some(user_id));
+    if own_entry {
+      channel_data.remove_latest_entry()
+    } else {
+      None
+    }
+  });
+  let last_entry = match last_entry {
+    Some(entry) => entry,
+    None => {
+      return Some(CommandResponse::private("There is nothing to regenerate in this channel."));
+    }
+  };
+  let prompt = last_entry.user_message.clone();
+  info!(
+    "{} User {}#{} regenerate: {}",
+    tag, command.user.name, command.user.discriminator, prompt
+  );
+
+  // put the entry back if we can't replace it, so the history is left untouched
+  let restore_entry = |entry| {
+    handler.modify_channel_data(user_id, channel_id, |channel_data| {
+      channel_data.add_chat_history_entry(entry)
+    });
+  };
+
+  if let Err(message) = check_chat_allowed(handler, user_id, &prompt).await {
+    restore_entry(last_entry);
+    return Some(CommandResponse::private(message));
+  }
+
+  let response = match generate_ai_response(handler, &prompt, (user_id, channel_id)).await {
+    Ok(response) => response,
+    Err(e) => {
+      error!("{} Error generating response: {}", tag, e);
+      restore_entry(last_entry);
+      return Some(CommandResponse::generation_error(&e));
+    }
+  };
+  let message = reply_message(handler, user_id, &response);
+
+  // the reply is edited in place, unless it was deleted meanwhile
+  if !last_entry.reply_message_ids.is_empty() {
+    if let Ok(reply_message_ids) = edit_reply(ctx, channel_id, &last_entry.reply_message_ids, &message).await {
+      record_chat_history(handler, user_id, channel_id, &prompt, &response);
+      record_reply_messages(handler, user_id, channel_id, reply_message_ids);
+      return Some(CommandResponse::private("The reply has been regenerated."));
+    }
+    debug!("{} Could not edit the reply in place, posting it instead", tag);
+  }
+
+  let chat_privacy = handler
+    .with_user(user_id, |user| user.with_settings(|settings| settings.chat_privacy))
+    .unwrap();
+  let Ok(reply_message_ids) =
+    edit_original_message_or_create_followup(handler, ctx, command, message, &chat_privacy).await
+  else {
+    restore_entry(last_entry);
+    return None;
+  };
+
+  record_chat_history(handler, user_id, channel_id, &prompt, &response);
+  if !chat_privacy {
+    record_reply_messages(handler, user_id, channel_id, reply_message_ids);
+  }
+  None
+}
+
+/// Regenerates a reply when the user who asked for it reacts with `REGENERATE_REACTION`
+///
+/// Works like `/regenerate`, so only the latest exchange in the channel can be regenerated. The
+/// reply is edited in place and stays tracked with its new exchange.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the reaction
+/// * `reaction` - The Reaction data
+/// * `reply` - The tracked reply that was reacted to
+///
+pub async fn regenerate_reaction(handler: &HandlerStruct, ctx: &Context, reaction: &Reaction, reply: TrackedReply) {
+  let (user_id, channel_id) = (reply.user_id, reply.channel_id);
+  let tag = request_tag(reaction.message_id.0, user_id, channel_id);
+  let last_entry = handler.modify_channel_data(user_id, channel_id, |channel_data| {
+    let latest = channel_data
+      .chat_history
+      .last()
+      .is_some_and(|entry| entry.timestamp == reply.timestamp);
+    if latest {
+      channel_data.remove_latest_entry()
+    } else {
+      None
+    }
+  });
+  let Some(last_entry) = last_entry else {
+    debug!("{} Only the latest reply can be regenerated", tag);
+    return;
+  };
+  let prompt = last_entry.user_message.clone();
+  info!("{} Regenerate by reaction: {}", tag, prompt);
+
+  // put the entry back if we can't replace it, so the history is left untouched
+  let restore_entry = |entry| {
+    handler.modify_channel_data(user_id, channel_id, |channel_data| {
+      channel_data.add_chat_history_entry(entry)
+    });
+  };
+
+  if let Err(message) = check_chat_allowed(handler, user_id, &prompt).await {
+    restore_entry(last_entry);
+    if send_message(ctx, reaction.channel_id, &message, None).await.is_err() {
+      error!("{} Could not send the refusal", tag);
+    }
+    return;
+  }
+
+  let typing = start_typing(ctx.http.clone(), reaction.channel_id);
+  let response = generate_ai_response(handler, &prompt, (user_id, channel_id)).await;
+  drop(typing);
+  let response = match response {
+    Ok(response) => response,
+    Err(e) => {
+      error!("{} Error generating response: {}", tag, e);
+      restore_entry(last_entry);
+      return;
+    }
+  };
+  let message = reply_message(handler, user_id, &response);
+  // replies from before their messages were all stored only have the one that was reacted to
+  let reply_message_ids = if last_entry.reply_message_ids.is_empty() {
+    vec![reaction.message_id]
+  } else {
+    last_entry.reply_message_ids.clone()
+  };
+  let Ok(reply_message_ids) = edit_reply(ctx, reaction.channel_id, &reply_message_ids, &message).await else {
+    restore_entry(last_entry);
+    return;
+  };
+
+  record_chat_history(handler, user_id, channel_id, &prompt, &response);
+  record_reply_messages(handler, user_id, channel_id, reply_message_ids);
+  if let Some(timestamp) = latest_entry_timestamp(handler, user_id, channel_id) {
+    handler.track_reply(reaction.message_id, TrackedReply { timestamp, ..reply });
+  }
+  // removing the reaction lets the user regenerate again, it needs Manage Messages in servers
+  if let Err(why) = reaction.delete(&ctx.http).await {
+    debug!("{} Could not remove the reaction: {:?}", tag, why);
+  }
+}
+
+/// Deletes a reply when the user who asked for it reacts with `DELETE_REACTION`
+///
+/// The exchange of the reply is removed from the chat history as well, so the AI forgets it. The
+/// other messages of a long reply are deleted with the one that was reacted to.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the reaction
+/// * `reaction` - The Reaction data
+/// * `reply` - The tracked reply that was reacted to
+///
+pub async fn delete_reaction(handler: &HandlerStruct, ctx: &Context, reaction: &Reaction, reply: TrackedReply) {
+  let tag = request_tag(reaction.message_id.0, reply.user_id, reply.channel_id);
+  if let Err(why) = reaction.channel_id.delete_message(&ctx.http, reaction.message_id).await {
+    error!("{} Error deleting the reply: {:?}", tag, why);
+    return;
+  }
+  handler.untrack_reply(reaction.message_id);
+  let removed = handler.modify_channel_data(reply.user_id, reply.channel_id, |channel_data| {
+    channel_data.remove_entry_at(reply.timestamp)
+  });
+  // replies from before their messages were all stored only have the one that was reacted to
+  let other_message_ids = removed
+    .as_ref()
+    .map(|entry| entry.reply_message_ids.clone())
+    .unwrap_or_default()
+    .into_iter()
+    .filter(|message_id| *message_id != reaction.message_id);
+  for message_id in other_message_ids {
+    if let Err(why) = reaction.channel_id.delete_message(&ctx.http, message_id).await {
+      debug!("{} Could not delete the message {}: {:?}", tag, message_id, why);
+    }
+  }
+  info!("{} Deleted the reply, its exchange was {}", tag, if removed.is_some() { "forgotten" } else { "already gone" });
+}
+
+/// The prompt asking the AI to continue its truncated response
+const CONTINUE_PROMPT: &str = "Continue exactly where your last message was cut off, without repeating any of it.";
+
+/// Handles the `/continue` command
+///
+/// Asks the AI to continue the latest response in the channel when it was cut off at the token
+/// limit. The continuation is appended to the same exchange in the chat history.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn continue_command(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let user_id = command.user.id;
+  let channel_id = command.channel_id;
+  let tag = command_tag(command);
+
+  // in a shared channel only the user's own latest exchange can be continued
+  let last_entry = handler
+    .get_channel_data(user_id, channel_id)
+    .and_then(|channel_data| channel_data.chat_history.last().cloned())
+    .filter(|entry| entry.truncated && (entry.author.is_none() || entry.author == Some(user_id)));
+  let Some(last_entry) = last_entry else {
+    return Some(CommandResponse::private("There is nothing to continue in this channel."));
+  };
+  info!(
+    "{} User {}#{} continue",
+    tag, command.user.name, command.user.discriminator
+  );
+
+  if let Err(message) = check_chat_allowed(handler, user_id, CONTINUE_PROMPT).await {
+    return Some(CommandResponse::private(message));
+  }
+
+  let typing = start_typing(ctx.http.clone(), channel_id);
+  let response = generate_ai_response(handler, CONTINUE_PROMPT, (user_id, channel_id)).await;
+  drop(typing);
+  let response = match response {
+    Ok(response) => response,
+    Err(e) => {
+      error!("{} Error generating response: {}", tag, e);
+      return Some(CommandResponse::generation_error(&e));
+    }
+  };
+  let message = reply_message(handler, user_id, &response);
+
+  let chat_privacy = handler
+    .with_user(user_id, |user| user.with_settings(|settings| settings.chat_privacy))
+    .unwrap();
+  let Ok(reply_message_ids) =
+    edit_original_message_or_create_followup(handler, ctx, command, message, &chat_privacy).await
+  else {
+    return None;
+  };
+
+  record_continuation(handler, user_id, channel_id, &response);
+  // the continuation is regenerated together with the reply it continues, when that one is kept
+  if !chat_privacy && !last_entry.reply_message_ids.is_empty() {
+    record_reply_messages(handler, user_id, channel_id, reply_message_ids);
+  }
+  None
+}
+
+/// Handles the `/ask` command
+///
+/// Answers a one-off question privately. The chat history of the channel is neither sent with the
+/// question nor updated with it, but the tokens still count toward the user's usage.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn ask_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let prompt = command
+    .data
+    .options
+    .first()
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("");
+  let user_id = command.user.id;
+  let channel_id = command.channel_id;
+  let tag = command_tag(command);
+  info!(
+    "{} User {}#{} ask: {}",
+    tag, command.user.name, command.user.discriminator, prompt
+  );
+  handler.get_metrics().chat_requests.inc();
+
+  if let Err(message) = check_prompt_length(&handler.get_config(), prompt) {
+    return Some(CommandResponse::private(message));
+  }
+  if let Err(message) = check_chat_allowed(handler, user_id, prompt).await {
+    return Some(CommandResponse::private(message));
+  }
+
+  let response = match generate_one_shot_response(handler, prompt, (user_id, channel_id)).await {
     Ok(response) => response,
     Err(e) => {
-      error!("Error generating response: {:?}", e);
+      error!("{} Error generating response: {}", tag, e);
+      return Some(CommandResponse::generation_error(&e));
+    }
+  };
+  let message = reply_message(handler, user_id, &response);
+  // the answer is sent, so the tokens are billed even if showing it fails
+  record_usage(handler, user_id, &response);
+  Some(CommandResponse::private(message))
+}
+
+/// Handles the `/system` command
+///
+/// Sets or clears a custom system prompt for the current channel. While set, it is
+/// used instead of the personality prompt.
+///
+/// With `shared_channel_history` the prompt is used for everyone in the channel, so only server
+/// administrators can change it.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn system_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let user_id = command.user.id;
+  let channel_id = command.channel_id;
+  if handler.get_config().shared_channel_history && command.guild_id.is_some() && !is_admin(command) {
+    return Some(CommandResponse::private(
+      "Only administrators can change the system prompt of a shared channel.",
+    ));
+  }
+  let subcommand = match command.data.options.first() {
+    Some(subcommand) => subcommand,
+    None => return None,
+  };
+  let system_prompt = match subcommand.name.as_str() {
+    "set" => subcommand
+      .options
+      .first()
+      .and_then(|opt| opt.value.as_ref())
+      .and_then(|value| value.as_str())
+      .map(|value| value.to_string()),
+    _ => None,
+  };
+  let message = match &system_prompt {
+    Some(_) => "The system prompt for this channel has been set.".to_string(),
+    None => "The system prompt for this channel has been cleared.".to_string(),
+  };
+
+  handler.modify_channel_data(user_id, channel_id, |channel_data| {
+    channel_data.set_system_prompt(system_prompt)
+  });
+
+  let chat_privacy = handler.with_user(user_id, |user| {
+    user.with_settings(|settings| settings.chat_privacy)
+  });
+  let chat_privacy = chat_privacy.unwrap();
+  Some(CommandResponse::new(message, chat_privacy))
+}
+
+/// Handles the `/session` command
+///
+/// Starts, switches between and lists the named sessions of the current channel. Each session
+/// has its own chat history, and `/chat` continues the active one.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn session_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let user_id = command.user.id;
+  let channel_id = command.channel_id;
+  let subcommand = command.data.options.first()?;
+  let name = subcommand
+    .options
+    .iter()
+    .find(|option| option.name == "name")
+    .and_then(|option| option.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or_default();
+  let result = match subcommand.name.as_str() {
+    "new" => handler
+      .modify_channel_data(user_id, channel_id, |channel_data| channel_data.new_session(name))
+      .map(|_| format!("Started the session {}.", name.trim())),
+    "switch" => handler
+      .modify_channel_data(user_id, channel_id, |channel_data| channel_data.switch_session(name))
+      .map(|_| format!("Switched to the session {}.", name.trim())),
+    _ => {
+      let (active, names) = handler
+        .get_channel_data(user_id, channel_id)
+        .map(|channel_data| (channel_data.get_active_session().to_string(), channel_data.session_names()))
+        .unwrap_or_else(|| (DEFAULT_SESSION.to_string(), vec![DEFAULT_SESSION.to_string()]));
+      let list = names
+        .iter()
+        .map(|session| {
+          if *session == active {
+            format!("- **{}** (active)", session)
+          } else {
+            format!("- {}", session)
+          }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+      Ok(format!("Sessions in this channel:\n{}", list))
+    }
+  };
+  let message = result.unwrap_or_else(|why| why);
+
+  let chat_privacy = handler.with_user(user_id, |user| {
+    user.with_settings(|settings| settings.chat_privacy)
+  });
+  let chat_privacy = chat_privacy.unwrap();
+  Some(CommandResponse::new(message, chat_privacy))
+}
+
+/// Handles the reply select menu sent by `/chat` when several replies were generated
+///
+/// Stores the picked reply in the chat history and replaces the candidates with it.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the component
+/// * `component` - The MessageComponentInteraction data
+///
+pub async fn choice_component(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  component: &MessageComponentInteraction,
+) {
+  let message_id = component.message.id;
+  let tag = request_tag(component.id.0, component.user.id, component.channel_id);
+
+  let pending = match handler.take_pending_choices(message_id) {
+    Some(pending) => pending,
+    None => {
+      debug!("{} No pending replies for message {}", tag, message_id);
       return;
     }
   };
-  let message = response
-    .choices()
+  // only the user who sent the prompt can pick the reply
+  if pending.user_id != component.user.id {
+    handler.add_pending_choices(message_id, pending);
+    if let Err(why) = component
+      .create_interaction_response(&ctx.http, |response| {
+        response
+          .kind(InteractionResponseType::ChannelMessageWithSource)
+          .interaction_response_data(|message| {
+            message
+              .ephemeral(true)
+              .content("Only the user who sent the prompt can pick the reply.")
+          })
+      })
+      .await
+    {
+      error!("{} Error responding to component: {:?}", tag, why);
+    }
+    return;
+  }
+
+  let index = component
+    .data
+    .values
+    .first()
+    .and_then(|value| value.parse::<usize>().ok())
+    .unwrap_or(0);
+  let response = select_choice(&pending.response, index);
+  let message = reply_message(handler, pending.user_id, &response);
+  // a long reply replaces the candidates with its first part and continues in follow-up messages
+  let mut chunks = split_message(&message, DISCORD_MESSAGE_LIMIT).into_iter();
+  let first = chunks.next().unwrap_or_default();
+
+  if let Err(why) = component
+    .create_interaction_response(&ctx.http, |response| {
+      response
+        .kind(InteractionResponseType::UpdateMessage)
+        .interaction_response_data(|data| {
+          data
+            .content(first)
+            .allowed_mentions(no_mentions)
+            .components(|components| components)
+        })
+    })
+    .await
+  {
+    error!("{} Error updating the picked reply: {:?}", tag, why);
+    return;
+  }
+  let ephemeral = component
+    .message
+    .flags
+    .is_some_and(|flags| flags.contains(MessageFlags::EPHEMERAL));
+  for chunk in chunks {
+    if let Err(why) = component
+      .create_followup_message(&ctx.http, |message| build_reply_followup(message, chunk, ephemeral))
+      .await
+    {
+      error!("{} Error sending the rest of the picked reply: {:?}", tag, why);
+      break;
+    }
+  }
+
+  record_chat_history(
+    handler,
+    pending.user_id,
+    pending.channel_id,
+    &pending.prompt,
+    &response,
+  );
+}
+
+/// Handles the modal opened by `/persona-control add`
+///
+/// Creates the personality from the submitted fields in the personas of the guild, or updates it
+/// if one with the same name exists.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the modal
+/// * `modal` - The ModalSubmitInteraction data
+///
+pub async fn persona_add_modal(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  modal: &ModalSubmitInteraction,
+) {
+  let tag = request_tag(modal.id.0, modal.user.id, modal.channel_id);
+  let field = |custom_id: &str| {
+    modal
+      .data
+      .components
+      .iter()
+      .flat_map(|row| row.components.iter())
+      .find_map(|component| match component {
+        ActionRowComponent::InputText(input) if input.custom_id == custom_id => {
+          Some(input.value.clone())
+        }
+        _ => None,
+      })
+      .unwrap_or_default()
+  };
+  let name = field("name");
+  let description = field("description");
+  let prompt = field("prompt");
+  // the display name and avatar are optional, empty fields fall back to the bot's own
+  let optional = |custom_id: &str| Some(field(custom_id).trim().to_string()).filter(|value| !value.is_empty());
+  let display_name = optional("display_name");
+  let avatar_url = optional("avatar_url");
+  let max_context_entries = modal
+    .data
+    .custom_id
+    .strip_prefix("persona-add:")
+    .and_then(|context| context.parse::<usize>().ok());
+  debug!("{} Adding personality {:?}: {:?}", tag, name, description);
+  // /persona-control is not available in DMs, so the modal always comes from a guild
+  let Some(guild_id) = modal.guild_id else {
+    error!("{} Personality submitted outside of a guild", tag);
+    return;
+  };
+
+  let content = if avatar_url
+    .as_ref()
+    .is_some_and(|url| !url.starts_with("https://") && !url.starts_with("http://"))
+  {
+    "The avatar URL has to start with https:// or http://.".to_string()
+  } else {
+    handler
+      .modify_personas(guild_id, |personas| {
+        if let Some(personality) = personas.iter_mut().find(|p| p.name == name) {
+          personality.set_prompt(prompt.clone());
+          personality.description = description.clone();
+          personality.display_name = display_name.clone();
+          personality.avatar_url = avatar_url.clone();
+          personality.max_context_entries = max_context_entries;
+        } else {
+          let mut personality = Personality::new(
+            name.clone(),
+            prompt.clone(),
+            description.clone(),
+          );
+          personality.display_name = display_name.clone();
+          personality.avatar_url = avatar_url.clone();
+          personality.max_context_entries = max_context_entries;
+          personas.push(personality);
+        }
+      })
+      .unwrap_or_else(|err| error!("{} Error modifying personality: {:?}", tag, err));
+    format!("Personality {} has been created.", name)
+  };
+
+  if let Err(why) = modal
+    .create_interaction_response(&ctx.http, |response| {
+      response
+        .kind(InteractionResponseType::ChannelMessageWithSource)
+        .interaction_response_data(|message| message.ephemeral(true).content(content))
+    })
+    .await
+  {
+    error!("{} Error responding to modal: {:?}", tag, why);
+  }
+}
+
+/// Suggests the personas of the guild while a personality option is typed
+///
+/// The personas differ per guild, so they can't be registered as choices of the global commands.
+/// `/personality` also suggests the user's private personalities.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the autocomplete
+/// * `autocomplete` - The AutocompleteInteraction data
+///
+pub async fn persona_autocomplete(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  autocomplete: &AutocompleteInteraction,
+) {
+  // the focused option is nested in a subcommand for /persona-control remove
+  fn focused(options: &[CommandDataOption]) -> Option<&CommandDataOption> {
+    options
+      .iter()
+      .find_map(|option| if option.focused { Some(option) } else { focused(&option.options) })
+  }
+  let typed = focused(&autocomplete.data.options)
+    .and_then(|option| option.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("");
+  let mut personas = handler.personas_for(autocomplete.guild_id);
+  if autocomplete.data.name == "personality" {
+    if let Some(custom) = handler.with_user(autocomplete.user.id, |user| {
+      user.with_settings(|settings| settings.custom_personalities.clone())
+    }) {
+      personas.extend(custom);
+    }
+  }
+
+  let suggestions = persona_suggestions(&personas, typed);
+  if let Err(why) = autocomplete
+    .create_autocomplete_response(&ctx.http, |response| {
+      for name in suggestions {
+        response.add_string_choice(&name, &name);
+      }
+      response
+    })
+    .await
+  {
+    error!("Error sending the personality suggestions: {:?}", why);
+  }
+}
+
+/// Handles the `/config` command
+///
+/// Changes the user's chat settings. Options that are left out are not changed.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn config_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let user_id = command.user.id;
+  let choices = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "choices")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_u64());
+  let history = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "history")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_u64());
+  let temperature = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "temperature")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_f64());
+  let top_p = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "top_p")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_f64());
+  let presence_penalty = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "presence_penalty")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_f64());
+  let frequency_penalty = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "frequency_penalty")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_f64());
+  let language = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "language")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .map(|value| value.trim().to_string());
+  let show_tokens = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "show_tokens")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_bool());
+  let json_mode = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "json_mode")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_bool());
+  let seed = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "seed")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str());
+  let template = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "template")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str());
+
+  let mut errors = Vec::new();
+  handler
+    .modify_user(user_id, |user| {
+      user.modify_settings(|settings| {
+        if let Some(choices) = choices {
+          settings.set_choices(u32::try_from(choices).unwrap_or(u32::MAX));
+        }
+        if let Some(history) = history {
+          settings.set_max_history_entries(usize::try_from(history).unwrap_or(usize::MAX));
+        }
+        if let Some(Err(e)) = temperature.map(|temperature| settings.set_temperature(temperature as f32)) {
+          errors.push(e);
+        }
+        if let Some(Err(e)) = top_p.map(|top_p| settings.set_top_p(top_p as f32)) {
+          errors.push(e);
+        }
+        if let Some(Err(e)) = presence_penalty.map(|penalty| settings.set_presence_penalty(penalty as f32)) {
+          errors.push(e);
+        }
+        if let Some(Err(e)) = frequency_penalty.map(|penalty| settings.set_frequency_penalty(penalty as f32)) {
+          errors.push(e);
+        }
+        if let Some(show_tokens) = show_tokens {
+          settings.set_show_token_footer(show_tokens);
+        }
+        if let Some(Err(e)) = json_mode.map(|json_mode| settings.set_json_mode(json_mode)) {
+          errors.push(e);
+        }
+        if let Some(language) = language {
+          settings.set_locale(Some(language));
+        }
+        if let Some(Err(e)) = seed.map(|seed| settings.set_by_key("seed", seed, &[])) {
+          errors.push(e);
+        }
+        if let Some(Err(e)) = template.map(|template| settings.set_by_key("template", template, &[])) {
+          errors.push(e);
+        }
+      })
+    })
+    .unwrap_or_else(|e| {
+      error!("{} Error modifying user: {:?}", command_tag(command), e);
+    });
+
+  if !errors.is_empty() {
+    // the error is only for the user
+    return Some(CommandResponse::private(errors.join("\n")));
+  }
+
+  let (chat_privacy, message) = handler
+    .with_user(user_id, |user| {
+      user.with_settings(|settings| {
+        (
+          settings.chat_privacy,
+          format!(
+            "Replies to pick from: {}\nExchanges kept per channel: {}\nTemperature: {}\nTop p: {}\nPresence penalty: {}\nFrequency penalty: {}\nShow tokens: {}\nJSON mode: {}\nSeed: {}\nLanguage: {}\nTemplate: {}",
+            settings.get_choices(),
+            match settings.get_max_history_entries() {
+              0 => "all".to_string(),
+              entries => entries.to_string(),
+            },
+            settings.get_temperature(),
+            settings.get_top_p(),
+            settings.get_presence_penalty(),
+            settings.get_frequency_penalty(),
+            settings.get_show_token_footer(),
+            settings.get_json_mode(),
+            settings
+              .get_seed()
+              .map(|seed| seed.to_string())
+              .unwrap_or_else(|| "not set".to_string()),
+            settings
+              .get_locale()
+              .map(|locale| language_name(locale))
+              .unwrap_or_else(|| "not set".to_string()),
+            settings
+              .get_prompt_template()
+              .cloned()
+              .unwrap_or_else(|| "not set".to_string())
+          ),
+        )
+      })
+    })
+    .unwrap();
+  Some(CommandResponse::new(message, chat_privacy))
+}
+
+/// Handles the `/settings` command
+///
+/// `/settings show` sends the user's settings as JSON, and `/settings set` changes one of the
+/// `SETTINGS_KEYS` before showing them. The other settings commands keep working as before.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn settings_command(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let user_id = command.user.id;
+  let tag = command_tag(command);
+  let subcommand = command.data.options.first();
+  if let Some(set) = subcommand.filter(|subcommand| subcommand.name == "set") {
+    let option = |name: &str| {
+      set
+        .options
+        .iter()
+        .find(|opt| opt.name == name)
+        .and_then(|opt| opt.value.as_ref())
+        .and_then(|value| value.as_str())
+        .unwrap_or("")
+        .to_string()
+    };
+    let (key, value) = (option("key"), option("value"));
+    let personas = handler.personas_for(command.guild_id);
+    let mut result = Ok(());
+    handler
+      .modify_user(user_id, |user| {
+        user.modify_settings(|settings| result = settings.set_by_key(&key, &value, &personas))
+      })
+      .unwrap_or_else(|e| {
+        error!("{} Error modifying user: {:?}", tag, e);
+      });
+    if let Err(message) = result {
+      return Some(CommandResponse::private(message));
+    }
+    info!("{} Set {} to {:?}", tag, key, value);
+  }
+
+  let settings = handler
+    .with_user(user_id, |user| {
+      user.with_settings(|settings| {
+        json!({
+          "privacy": if settings.get_chat_privacy() { "private" } else { "public" },
+          "personality": settings.get_personality().name,
+          "model": settings.get_model().get_name(),
+          "max_tokens": MAX_COMPLETION_TOKENS,
+          "temperature": settings.get_temperature(),
+          "top_p": settings.get_top_p(),
+          "presence_penalty": settings.get_presence_penalty(),
+          "frequency_penalty": settings.get_frequency_penalty(),
+          "choices": settings.get_choices(),
+          "history": settings.get_max_history_entries(),
+          "show_tokens": settings.get_show_token_footer(),
+          "json_mode": settings.get_json_mode(),
+          "seed": settings.get_seed(),
+          "template": settings.get_prompt_template(),
+          "length": settings.get_response_length().map(|length| length.name()),
+          "language": settings.get_locale(),
+          "token_budget": settings.get_token_budget(),
+          "image_quota": settings.get_image_quota(),
+        })
+      })
+    })
+    .unwrap();
+  let settings = serde_json::to_string_pretty(&settings).unwrap_or_default();
+  if let Err(why) = command
+    .create_followup_message(&ctx.http, |message| {
+      message.ephemeral(true).embed(|embed| {
+        embed
+          .title("Your settings")
+          .description(format!("```json\n{}\n```", settings))
+          .footer(|footer| footer.text("Change them with /settings set"))
+      })
+    })
+    .await
+  {
+    error!("{} Error sending follow-up message: {:?}", tag, why);
+  }
+  None
+}
+
+/// Handles the `/help` command
+///
+/// Sends the list of commands, plus the admin commands to admins, with the user's current model
+/// and personality.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Context struct provided by Serenity
+/// * `command` - The ApplicationCommandInteraction struct provided by Serenity
+pub async fn help_command(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let tag = command_tag(command);
+  let (model, personality) = handler
+    .with_user(command.user.id, |user| {
+      user.with_settings(|settings| {
+        (settings.get_model().get_name(), settings.get_personality().name)
+      })
+    })
+    .unwrap_or_default();
+  let is_admin = is_admin(command);
+  if let Err(why) = command
+    .create_followup_message(&ctx.http, |message| {
+      message.ephemeral(true).embed(|embed| {
+        embed
+          .title("Commands")
+          .description(command_list(false))
+          .field("Model", model, true)
+          .field("Personality", personality, true);
+        if is_admin {
+          embed.field("Admin commands", command_list(true), false);
+        }
+        embed
+      })
+    })
+    .await
+  {
+    error!("{} Error sending follow-up message: {:?}", tag, why);
+  }
+  None
+}
+
+/// Handles the `/debug-prompt` command
+///
+/// Sends the messages the AI would get for a prompt in the channel, as JSON only the admin can see:
+/// the system message, the chat history trimmed to the token limit and the prompt. Nothing is sent
+/// to the AI, except to embeddings with `SEMANTIC_MEMORY`.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Context struct provided by Serenity
+/// * `command` - The ApplicationCommandInteraction struct provided by Serenity
+pub async fn debug_prompt_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let prompt = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "prompt")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("(your next message)");
+  let messages = build_chat_messages(handler, prompt, (command.user.id, command.channel_id), true).await;
+  let tokens: u64 = messages.iter().map(|message| estimate_tokens(&message.content.as_text())).sum();
+  let message = format!(
+    "{} messages, about {} tokens:\n```json\n{}\n```",
+    messages.len(),
+    tokens,
+    serde_json::to_string_pretty(&messages).unwrap_or_default()
+  );
+  Some(CommandResponse::private(message))
+}
+
+/// Handles the `/whoami` command
+///
+/// Sends the data stored for the user as JSON only they can see, with the chat histories reduced
+/// to their size.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Context struct provided by Serenity
+/// * `command` - The ApplicationCommandInteraction struct provided by Serenity
+pub async fn whoami_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let tag = command_tag(command);
+  let Some(summary) = handler.with_user(command.user.id, |user| user.to_summary_json()) else {
+    error!("{} User not found", tag);
+    return None;
+  };
+  let summary = serde_json::to_string_pretty(&summary).unwrap_or_default();
+  Some(CommandResponse::private(format!("```json\n{}\n```", summary)))
+}
+
+/// Handles the `/cost` command
+///
+/// Sends the estimated lifetime spend of the user only to them, priced with `MODEL_PRICES`.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Context struct provided by Serenity
+/// * `command` - The ApplicationCommandInteraction struct provided by Serenity
+pub async fn cost_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let usage = handler.with_user(command.user.id, |user| {
+    user.with_usage(|usage| {
+      // the time of the last chat is only known once the user has chatted
+      let last_chat = (usage.chat_count > 0).then_some(usage.last_chat);
+      (usage.get_estimated_cost(), usage.get_total_tokens(), last_chat)
+    })
+  });
+  let message = match usage {
+    Some((cost, tokens, last_chat)) => format!(
+      "You have used {} tokens, about ${:.4}.{}\n_This is an estimate based on the list prices of the models, your actual bill may differ._",
+      format_number(tokens as u64, &command.locale),
+      cost,
+      last_chat
+        .map(|last_chat| format!(" Your last message was {}.", discord_timestamp(last_chat)))
+        .unwrap_or_default()
+    ),
+    None => "You haven't used any tokens yet.".to_string(),
+  };
+  Some(CommandResponse::private(message))
+}
+
+/// Handles the `/export` command
+///
+/// Sends the user's chat history of every channel as a JSON file only they can see.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn export_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let tag = command_tag(command);
+  let channel_history = handler.export_channel_history(command.user.id);
+
+  if channel_history
+    .values()
+    .all(|channel_data| channel_data.chat_history.is_empty())
+  {
+    return Some(CommandResponse::private("There is nothing to export."));
+  }
+
+  let json = match serde_json::to_vec_pretty(&channel_history) {
+    Ok(json) => json,
+    Err(why) => {
+      error!("{} Error serializing chat history: {:?}", tag, why);
+      return None;
+    }
+  };
+  Some(CommandResponse::private("Here is your chat history.").with_attachment("chat-history.json", json))
+}
+
+/// Handles the `/transcript` command
+///
+/// Sends the chat history of the current channel as a Markdown file, respecting the user's chat privacy.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn transcript_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let user_id = command.user.id;
+  let chat_privacy = handler
+    .with_user(user_id, |user| user.with_settings(|settings| settings.get_chat_privacy()))
+    .unwrap_or_default();
+  let chat_history = handler
+    .get_channel_data(user_id, command.channel_id)
+    .map(|channel_data| channel_data.chat_history)
+    .unwrap_or_default();
+
+  if chat_history.is_empty() {
+    return Some(CommandResponse::new("There is no chat history in this channel.", chat_privacy));
+  }
+
+  let transcript = render_transcript(&chat_history, user_id);
+  Some(
+    CommandResponse::new("Here is the transcript of this channel.", chat_privacy)
+      .with_attachment("transcript.md", transcript.into_bytes()),
+  )
+}
+
+/// Adds the previous and next buttons of `/history`, their IDs carry the page they show
+fn history_buttons(components: &mut CreateComponents, page: usize, pages: usize) -> &mut CreateComponents {
+  components.create_action_row(|row| {
+    row
+      .create_button(|button| {
+        button
+          .custom_id(format!("history:{}", page - 1))
+          .label("Previous")
+          .style(ButtonStyle::Secondary)
+          .disabled(page <= 1)
+      })
+      .create_button(|button| {
+        button
+          .custom_id(format!("history:{}", page + 1))
+          .label("Next")
+          .style(ButtonStyle::Secondary)
+          .disabled(page >= pages)
+      })
+  })
+}
+
+/// Handles the `/history` command
+///
+/// Shows a page of the chat history of the current channel only to the user, with buttons to
+/// browse the other pages, see `history_component`.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn history_command(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let page = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "page")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_u64())
+    .unwrap_or(1) as usize;
+  let chat_history = handler
+    .get_channel_data(command.user.id, command.channel_id)
+    .map(|channel_data| channel_data.chat_history)
+    .unwrap_or_default();
+
+  if chat_history.is_empty() {
+    return Some(CommandResponse::private("There is no chat history in this channel."));
+  }
+
+  let pages = history_page_count(&chat_history);
+  let page = page.clamp(1, pages);
+  let content = render_history_page(&chat_history, page, &command.locale);
+  if let Err(why) = command
+    .create_followup_message(&ctx.http, |message| {
+      message
+        .ephemeral(true)
+        .content(content)
+        .allowed_mentions(no_mentions)
+        .components(|components| history_buttons(components, page, pages))
+    })
+    .await
+  {
+    error!("{} Error sending follow-up message: {:?}", command_tag(command), why);
+  }
+  None
+}
+
+/// Handles the previous and next buttons sent by `/history`
+///
+/// Replaces the page with the one of the button, read again so new exchanges show up.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the component
+/// * `component` - The MessageComponentInteraction data
+///
+pub async fn history_component(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  component: &MessageComponentInteraction,
+) {
+  let user_id = component.user.id;
+  let tag = request_tag(component.id.0, user_id, component.channel_id);
+  let page = component
+    .data
+    .custom_id
+    .strip_prefix("history:")
+    .and_then(|page| page.parse::<usize>().ok())
+    .unwrap_or(1);
+  let chat_history = handler
+    .get_channel_data(user_id, component.channel_id)
+    .map(|channel_data| channel_data.chat_history)
+    .unwrap_or_default();
+
+  let pages = history_page_count(&chat_history);
+  let page = page.clamp(1, pages);
+  let content = if chat_history.is_empty() {
+    "There is no chat history in this channel.".to_string()
+  } else {
+    render_history_page(&chat_history, page, &component.locale)
+  };
+  if let Err(why) = component
+    .create_interaction_response(&ctx.http, |response| {
+      response
+        .kind(InteractionResponseType::UpdateMessage)
+        .interaction_response_data(|data| {
+          data
+            .content(content)
+            .allowed_mentions(no_mentions)
+            .components(|components| history_buttons(components, page, pages))
+        })
+    })
+    .await
+  {
+    error!("{} Error responding to component: {:?}", tag, why);
+  }
+}
+
+/// Handles the `/forget` command
+///
+/// Asks the user to confirm before all of their stored data is deleted.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn forget_command(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  // mention /export so the user can keep a copy of their chat history first
+  let export = match handler.get_command_id("export").await {
+    Some(command_id) => format!("</export:{}>", command_id),
+    None => "/export".to_string(),
+  };
+  let content = format!(
+    "This deletes your settings, usage and chat history in every channel. Use {} first to keep a copy. Are you sure?",
+    export
+  );
+  if let Err(why) = command
+    .create_followup_message(&ctx.http, |message| {
+      message
+        .ephemeral(true)
+        .content(content)
+        .components(|components| {
+          components.create_action_row(|row| {
+            row
+              .create_button(|button| {
+                button
+                  .custom_id("forget-confirm")
+                  .label("Delete my data")
+                  .style(ButtonStyle::Danger)
+              })
+              .create_button(|button| {
+                button
+                  .custom_id("forget-cancel")
+                  .label("Cancel")
+                  .style(ButtonStyle::Secondary)
+              })
+          })
+        })
+    })
+    .await
+  {
+    error!("{} Error sending follow-up message: {:?}", command_tag(command), why);
+  }
+  None
+}
+
+/// Handles the confirmation buttons sent by `/forget`
+///
+/// Deletes the user from memory and from the state file when confirmed.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the component
+/// * `component` - The MessageComponentInteraction data
+///
+pub async fn forget_component(
+  handler: &HandlerStruct,
+  ctx: &Context,
+  component: &MessageComponentInteraction,
+) {
+  let user_id = component.user.id;
+  let tag = request_tag(component.id.0, user_id, component.channel_id);
+
+  let message = if component.data.custom_id == "forget-confirm" {
+    handler.remove_user(user_id);
+    // rewrite the state file right away so the data is gone from storage too
+    if let Err(why) = handler.save_state() {
+      error!("{} Error saving state: {:?}", tag, why);
+    }
+    info!("{} Deleted all data of the user", tag);
+    "All of your data has been deleted."
+  } else {
+    "Nothing was deleted."
+  };
+
+  if let Err(why) = component
+    .create_interaction_response(&ctx.http, |response| {
+      response
+        .kind(InteractionResponseType::UpdateMessage)
+        .interaction_response_data(|data| {
+          data
+            .content(message)
+            .allowed_mentions(no_mentions)
+            .components(|components| components)
+        })
+    })
+    .await
+  {
+    error!("{} Error responding to component: {:?}", tag, why);
+  }
+}
+
+/// Resets the chat history for the user and channel.
+///
+/// With `shared_channel_history` server administrators reset the history of everyone in the
+/// channel, the other users only remove their own messages from it.
+///
+/// # Arguments
+///
+/// * `user` - The user to set the chat privacy for
+/// * `_ctx` - The `Context` for accessing the Discord API.
+/// * `command` - The `ApplicationCommandInteraction` that triggered the reset command.
+///
+pub async fn reset_command(
+  user: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let channel_id = command.channel_id;
+  let user_id = command.user.id;
+
+  let shared = user.get_config().shared_channel_history && command.guild_id.is_some();
+  let message = if shared && !is_admin(command) {
+    user.modify_channel_data(user_id, channel_id, |channel_data| channel_data.clear_chat_history_of(user_id));
+    "Your messages have been removed from the chat history."
+  } else {
+    user.modify_channel_data(user_id, channel_id, |channel_data| channel_data.clear_chat_history());
+    "Chat history has been reset."
+  };
+  let chat_privacy = user.with_user(command.user.id, |user| {
+    user.with_settings(|settings| settings.chat_privacy)
+  });
+  let chat_privacy = chat_privacy.unwrap();
+  Some(CommandResponse::new(message, chat_privacy))
+}
+
+/// Handles the `/stop` command
+///
+/// Cancels the reply being generated for the user in the channel. The message it was generated
+/// for says "Cancelled." instead, see `CommandResponse::generation_error`.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn stop_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let tag = command_tag(command);
+  if handler.stop_generation((command.user.id, command.channel_id)) {
+    info!("{} Stopped the generation", tag);
+    Some(CommandResponse::private("Stopped the reply."))
+  } else {
+    Some(CommandResponse::private("No reply is being generated for you in this channel."))
+  }
+}
+
+/// Handles the `/private` command
+///
+/// Sets the user's chat privacy to private, making the AI responses ephemeral.
+///
+/// # Arguments
+///
+/// * `user` - The user to set the chat privacy for
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn private_command(
+  user: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  Some(set_chat_privacy(user, true, command))
+}
+
+/// Handles the `/public` command
+///
+/// Sets the user's chat privacy to public, making the AI responses visible to everyone.
+///
+/// # Arguments
+///
+/// * `chat_privacy` - The Arc<Mutex<HashMap<UserId, bool>>> containing chat privacy settings
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn public_command(
+  user: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  Some(set_chat_privacy(user, false, command))
+}
+
+/// Handles the `/concise` command
+///
+/// Toggles short replies, on top of the user's personality.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn concise_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  Some(toggle_response_length(handler, ResponseLength::Concise, command))
+}
+
+/// Handles the `/verbose` command
+///
+/// Toggles detailed replies, on top of the user's personality.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn verbose_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  Some(toggle_response_length(handler, ResponseLength::Verbose, command))
+}
+
+/// Handles the `/personality` command
+///
+/// Changes the personality of the AI
+///
+/// # Arguments
+///
+/// * `handler` - The Arc<Mutex<Handler>> containing the chat privacy settings
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+/// # Example
+// ///
+pub async fn personality_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  // fixme: The first message after changing the personality isnt set to the new personality
+  let user_id = command.user.id;
+  let personas = handler.personas_for(command.guild_id);
+
+  debug!("Personality command: {:#?}", command);
+  let new_personality = command
+    .data
+    .options
     .first()
-    .unwrap()
-    .message()
-    .content
-    .clone();
+    .and_then(|option| option.value.as_ref())
+		.and_then(|value| value.as_str())
+		.unwrap_or("default");
+  // the name is typed with suggestions, so it may not be a personality
+  let mut found = false;
+  handler
+    .modify_user(user_id, |user| {
+      user.modify_settings(|settings| {
+        let persona = personas
+          .iter()
+          .chain(settings.custom_personalities.iter())
+          .find(|persona| persona.name == new_personality)
+          .cloned();
+        if let Some(persona) = persona {
+          settings.set_personality(persona);
+          found = true;
+        }
+      });
+    })
+    .unwrap_or_else(|e| {
+      error!("Error modifying user: {:?}", e);
+    });
 
+  let message = if found {
+    format!("You are now using the {:?} personality.", new_personality)
+  } else {
+    format!("There is no {:?} personality.", new_personality)
+  };
   let chat_privacy = handler.with_user(user_id, |user| {
     user.with_settings(|settings| settings.chat_privacy)
   });
+  let chat_privacy = chat_privacy.unwrap();
+  Some(CommandResponse::new(message, chat_privacy))
+}
 
-  if (edit_original_message_or_create_followup(
-    ctx,
-    command,
-    message.clone(),
-    &chat_privacy.unwrap(),
-  )
-  .await)
-    .is_err()
-  {
-    return;
-  }
-
-  let usage = response.usage();
-  let total_tokens = usage.total_tokens();
-  let prompt_tokens = usage.prompt_tokens();
-  let completion_tokens = usage.completion_tokens();
-  let combined_message = format!("user: {}\n ai: {}", prompt, message);
-
-  let history_entry = UserChatHistoryEntry::new(
-    combined_message,
-    prompt.to_owned(),
-    message,
-    total_tokens,
-    prompt_tokens,
-    completion_tokens,
-  );
+/// Handles the `/model` command
+///
+/// Sets the model the user chats with, one of the chat models of the API key. JSON mode is turned
+/// off when the new model doesn't support it.
+///
+/// # Arguments
+///
+/// * `handler` - The HandlerStruct containing the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn model_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let user_id = command.user.id;
+  let name = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "name")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or_default()
+    .trim()
+    .to_lowercase();
+  let chat_privacy = handler
+    .with_user(user_id, |user| user.with_settings(|settings| settings.get_chat_privacy()))
+    .unwrap_or_default();
 
-  if !handler.user_exists(user_id) {
-    handler.add_user(user_id);
+  // the name is typed with suggestions, so it may not be a model of the API key
+  if !handler.get_chat_models().contains(&name) {
+    return Some(CommandResponse::new(
+      format!("There is no {:?} model, pick one of the suggestions.", name),
+      chat_privacy,
+    ));
   }
-
+  let model = Model::from_name(&name);
+  let mut json_mode_disabled = false;
   handler
     .modify_user(user_id, |user| {
-      let token_limit = user.with_settings(|settings| *settings.get_model().get_token_limit());
-      user.modify_usage(|usage| {
-        if !usage.contains_channel(channel_id) {
-          usage.add_channel(channel_id);
+      user.modify_settings(|settings| {
+        if settings.get_json_mode() && !model.supports_json_mode() {
+          settings.json_mode = false;
+          json_mode_disabled = true;
         }
-        // ?? why is this here?
-        // !? The only time the amount of tokens a user has used is at chat time when they are sent
-        // !? Even if the system message is changed by the personality command, it will still be the same amount of tokens
-        usage.add_total_tokens(history_entry.get_total_tokens());
-        usage.increase_chat_count();
-        debug!("total user tokens: {:?}", usage.get_total_tokens());
-
-        usage.modify_channel_data(channel_id, |channel_data| {
-          channel_data.add_chat_history_entry(history_entry.clone());
-          let user_tokens = channel_data.get_tokens_used();
-          debug!(
-            "User usage: {:?}, token_limit: {:?}",
-            user_tokens, token_limit
-          );
-          if user_tokens > &token_limit {
-            channel_data.remove_oldest_entry();
-          }
-        });
-      });
+        settings.model = model.clone();
+      })
     })
     .unwrap_or_else(|e| {
       error!("Error modifying user: {:?}", e);
     });
+
+  let mut message = format!("You are now chatting with {}.", model.get_name());
+  if json_mode_disabled {
+    message.push_str(" It can't reply with JSON, so JSON mode is off.");
+  }
+  Some(CommandResponse::new(message, chat_privacy))
 }
 
-/// Resets the chat history for the user and channel.
+/// Suggests the chat models of the API key while the user types the option of `/model`
 ///
 /// # Arguments
 ///
-/// * `user` - The user to set the chat privacy for
-/// * `ctx` - The `Context` for accessing the Discord API.
-/// * `command` - The `ApplicationCommandInteraction` that triggered the reset command.
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `ctx` - The Serenity Context for the autocomplete
+/// * `autocomplete` - The AutocompleteInteraction data
 ///
-pub async fn reset_command(
-  user: &HandlerStruct,
+pub async fn model_autocomplete(
+  handler: &HandlerStruct,
   ctx: &Context,
-  command: &ApplicationCommandInteraction,
+  autocomplete: &AutocompleteInteraction,
 ) {
-  let channel_id = command.channel_id;
+  let typed = autocomplete
+    .data
+    .options
+    .iter()
+    .find(|option| option.focused)
+    .and_then(|option| option.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("");
+  let suggestions = model_suggestions(&handler.get_chat_models(), typed);
+  if let Err(why) = autocomplete
+    .create_autocomplete_response(&ctx.http, |response| {
+      for name in suggestions {
+        response.add_string_choice(&name, &name);
+      }
+      response
+    })
+    .await
+  {
+    error!("Error sending the model suggestions: {:?}", why);
+  }
+}
+
+/// Handles the `/addpersonality` command
+///
+/// Creates a private personality for the user and makes it their active personality.
+/// Unlike `/persona-control`, the personality is only stored on the user who created it.
+///
+/// # Arguments
+///
+/// * `handler` - The HandlerStruct containing the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn add_personality_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
   let user_id = command.user.id;
+  debug!("Add personality command: {:#?}", command);
+
+  let name = command
+    .data
+    .options
+    .first()
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("");
+  let description = command
+    .data
+    .options
+    .get(1)
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("");
+  let prompt = command
+    .data
+    .options
+    .get(2)
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("");
+
+  let personality = Personality::new(
+    name.to_string(),
+    prompt.to_string(),
+    description.to_string(),
+  );
 
-  user
+  handler
     .modify_user(user_id, |user| {
-      user.modify_usage(|usage| usage.reset_channel_usage(channel_id));
+      user.modify_settings(|settings| {
+        settings.add_custom_personality(personality.clone());
+        settings.set_personality(personality);
+      })
     })
     .unwrap_or_else(|e| {
       error!("Error modifying user: {:?}", e);
     });
-  let chat_privacy = user.with_user(command.user.id, |user| {
+
+  let message = format!(
+    "Your private personality {:?} has been saved and is now active.",
+    name
+  );
+  let chat_privacy = handler.with_user(user_id, |user| {
     user.with_settings(|settings| settings.chat_privacy)
   });
   let chat_privacy = chat_privacy.unwrap();
-  let reset_message = "Chat history has been reset.".to_string();
-
-  if (create_followup_message(ctx, command, reset_message, &chat_privacy).await).is_err() {}
+  Some(CommandResponse::new(message, chat_privacy))
 }
 
-/// Handles the `/private` command
+/// Handles the `/imagine` command
 ///
-/// Sets the user's chat privacy to private, making the AI responses ephemeral.
+/// Generates an image from the user's prompt and posts the resulting image URL.
+/// Each generated image counts against the user's image quota.
 ///
 /// # Arguments
 ///
-/// * `user` - The user to set the chat privacy for
-/// * `ctx` - The Serenity Context for the command
+/// * `handler` - The HandlerStruct containing the bot's state
+/// * `_ctx` - The Serenity Context for the command
 /// * `command` - The ApplicationCommandInteraction data
 ///
-pub async fn private_command(
-  user: &HandlerStruct,
-  ctx: &Context,
+pub async fn imagine_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
   command: &ApplicationCommandInteraction,
-) {
-  set_chat_privacy(user, true, ctx, command).await;
+) -> Option<CommandResponse> {
+  let user_id = command.user.id;
+  let prompt = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "prompt")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("");
+  let size = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "size")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("512x512");
+
+  info!(
+    "User {}#{} imagine: {}",
+    command.user.name, command.user.discriminator, prompt
+  );
+
+  let (chat_privacy, images_generated, image_quota) = handler
+    .with_user(user_id, |user| {
+      (
+        user.with_settings(|settings| settings.chat_privacy),
+        user.with_usage(|usage| usage.get_images_generated()),
+        user.with_settings(|settings| settings.get_image_quota()),
+      )
+    })
+    .unwrap();
+
+  if images_generated >= image_quota {
+    let message = format!(
+      "You have reached your image quota of {} images.",
+      image_quota
+    );
+    return Some(CommandResponse::private(message));
+  }
+
+  let message = match generate_image(handler, prompt, size, user_id).await {
+    Ok(response) => match response.data.first() {
+      Some(image) => {
+        handler
+          .modify_user(user_id, |user| {
+            user.modify_usage(|usage| usage.increase_images_generated())
+          })
+          .unwrap_or_else(|e| {
+            error!("Error modifying user: {:?}", e);
+          });
+        image.url.clone()
+      }
+      None => "No image was generated.".to_string(),
+    },
+    Err(e) => e.user_message(),
+  };
+
+  Some(CommandResponse::new(message, chat_privacy))
 }
 
-/// Handles the `/public` command
+/// How many of the top token consumers `/stats` lists
+const STATS_TOP_USERS: usize = 5;
+
+/// Handles the `/stats` command
 ///
-/// Sets the user's chat privacy to public, making the AI responses visible to everyone.
+/// Shows the total tokens, the number of users and channels, and the top token consumers as an embed.
+/// Only available to administrators.
 ///
 /// # Arguments
 ///
-/// * `chat_privacy` - The Arc<Mutex<HashMap<UserId, bool>>> containing chat privacy settings
+/// * `handler` - The Handler struct that contains the bot's state
 /// * `ctx` - The Serenity Context for the command
 /// * `command` - The ApplicationCommandInteraction data
 ///
-pub async fn public_command(
-  user: &HandlerStruct,
+pub async fn stats_command(
+  handler: &HandlerStruct,
   ctx: &Context,
   command: &ApplicationCommandInteraction,
-) {
-  set_chat_privacy(user, false, ctx, command).await;
+) -> Option<CommandResponse> {
+  let tag = command_tag(command);
+  // the users are only locked while taking the snapshot, everything else is computed outside the lock
+  let mut snapshot = handler.usage_snapshot();
+
+  let total_tokens: u64 = snapshot.iter().map(|(_, tokens, _)| *tokens as u64).sum();
+  let user_count = snapshot.len();
+  let channel_count = snapshot
+    .iter()
+    .flat_map(|(_, _, channels)| channels.iter())
+    .collect::<HashSet<_>>()
+    .len();
+
+  snapshot.sort_by_key(|(_, tokens, _)| std::cmp::Reverse(*tokens));
+  let top_users = snapshot
+    .iter()
+    .take(STATS_TOP_USERS)
+    .filter(|(_, tokens, _)| *tokens > 0)
+    .enumerate()
+    .map(|(i, (user_id, tokens, _))| format!("{}. <@{}> - {} tokens", i + 1, user_id, tokens))
+    .collect::<Vec<_>>();
+  let top_users = if top_users.is_empty() {
+    "No tokens used yet.".to_string()
+  } else {
+    top_users.join("\n")
+  };
+
+  if let Err(why) = command
+    .create_followup_message(&ctx.http, |message| {
+      message.embed(|embed| {
+        embed
+          .title("Usage statistics")
+          .field("Total tokens", total_tokens, true)
+          .field("Users", user_count, true)
+          .field("Channels", channel_count, true)
+          .field("Top token consumers", top_users, false)
+      })
+    })
+    .await
+  {
+    error!("{} Error sending the statistics: {:?}", tag, why);
+  }
+  None
 }
 
-/// Handles the `/personality` command
+/// Handles the `/budget` command
 ///
-/// Changes the personality of the AI
+/// Sets the monthly token budget of a user. A budget of 0 removes the limit.
 ///
 /// # Arguments
 ///
-/// * `handler` - The Arc<Mutex<Handler>> containing the chat privacy settings
-/// * `ctx` - The Serenity Context for the command
+/// * `handler` - The HandlerStruct containing the bot's state
+/// * `_ctx` - The Serenity Context for the command
 /// * `command` - The ApplicationCommandInteraction data
 ///
-/// # Example
-// ///
-pub async fn personality_command(
+pub async fn budget_command(
   handler: &HandlerStruct,
-  ctx: &Context,
+  _ctx: &Context,
   command: &ApplicationCommandInteraction,
-) {
-  // debug!("Personality command: {:?}", command);
-  // fixme: The first message after changing the personality isnt set to the new personality
-  let user_id = command.user.id;
-  let personas = handler.get_personas();
-
-  debug!("Personality command: {:#?}", command);
-  let new_personality = command
+) -> Option<CommandResponse> {
+  let target_id = command
     .data
     .options
-    .get(0)
-    .and_then(|option| option.value.as_ref())
-		.and_then(|value| value.as_str())
-		.unwrap_or("default");
-  let new_personality = new_personality;
-  for persona in personas {
-    if persona.name == new_personality {
+    .iter()
+    .find(|opt| opt.name == "user")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .and_then(|value| value.parse::<u64>().ok())
+    .map(UserId);
+  let tokens = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "tokens")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_u64())
+    .unwrap_or(0);
+
+  let message = match target_id {
+    Some(target_id) => {
+      if !handler.user_exists(target_id) {
+        handler.add_user(target_id, command.guild_id);
+      }
+      let token_budget = u32::try_from(tokens).unwrap_or(u32::MAX);
       handler
-        .modify_user(user_id, |user| {
-          user.modify_settings(|settings| settings.set_personality(persona.clone()));
-          // info!("Personality command selected: {:?}", persona.name)
+        .modify_user(target_id, |user| {
+          user.modify_settings(|settings| settings.set_token_budget(token_budget))
         })
         .unwrap_or_else(|e| {
           error!("Error modifying user: {:?}", e);
         });
+      if token_budget == 0 {
+        format!("Removed the monthly token budget of <@{}>.", target_id)
+      } else {
+        format!(
+          "Set the monthly token budget of <@{}> to {} tokens.",
+          target_id, token_budget
+        )
+      }
     }
-  }
+    None => "Could not find that user.".to_string(),
+  };
 
-  let message = format!("You are now using the {:?} personality.", new_personality);
-  let chat_privacy = handler.with_user(user_id, |user| {
+  let chat_privacy = handler.with_user(command.user.id, |user| {
+    user.with_settings(|settings| settings.chat_privacy)
+  });
+  let chat_privacy = chat_privacy.unwrap();
+  Some(CommandResponse::new(message, chat_privacy))
+}
+
+/// Handles the `/guild-default` command
+///
+/// Sets the personality that users of the guild start with when the bot first sees them.
+/// Users who already exist keep their personality.
+///
+/// # Arguments
+///
+/// * `handler` - The HandlerStruct containing the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn guild_default_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let name = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "persona")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("default");
+  let persona = command.guild_id.and_then(|guild_id| {
+    handler
+      .get_personas(guild_id)
+      .into_iter()
+      .find(|persona| persona.name == name)
+  });
+
+  let message = match (command.guild_id, persona) {
+    (None, _) => "The default personality can only be set in a server.".to_string(),
+    (_, None) => format!("There is no {:?} personality.", name),
+    (Some(guild_id), Some(persona)) => {
+      info!("{} Guild default personality set to {:?}", command_tag(command), name);
+      handler.set_guild_default(guild_id, persona);
+      format!("New users of this server now start with the {:?} personality.", name)
+    }
+  };
+
+  let chat_privacy = handler.with_user(command.user.id, |user| {
+    user.with_settings(|settings| settings.chat_privacy)
+  });
+  let chat_privacy = chat_privacy.unwrap();
+  Some(CommandResponse::new(message, chat_privacy))
+}
+
+/// Handles the `/channels` command
+///
+/// Allows, denies or removes a channel from the channels the bot chats in, or lists them.
+/// The changes last until the bot restarts, see `ALLOWED_CHANNELS` and `DENIED_CHANNELS` to keep them.
+///
+/// # Arguments
+///
+/// * `handler` - The HandlerStruct containing the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn channels_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let action = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "action")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .unwrap_or("list");
+  let channel_id = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "channel")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_str())
+    .and_then(|value| value.parse::<u64>().ok())
+    .unwrap_or(command.channel_id.0);
+
+  let message = handler.modify_channel_filter(|filter| match action {
+    "allow" => {
+      filter.allow(channel_id);
+      format!("I now chat in <#{}>.", channel_id)
+    }
+    "deny" => {
+      filter.deny(channel_id);
+      format!("I no longer chat in <#{}>.", channel_id)
+    }
+    "remove" => {
+      filter.remove(channel_id);
+      format!("Removed <#{}> from the allowed and denied channels.", channel_id)
+    }
+    _ => {
+      let mention = |ids: &[u64]| {
+        ids
+          .iter()
+          .map(|id| format!("<#{}>", id))
+          .collect::<Vec<String>>()
+          .join(", ")
+      };
+      let allowed = if filter.allowed.is_empty() {
+        "every channel".to_string()
+      } else {
+        mention(&filter.allowed)
+      };
+      let denied = if filter.denied.is_empty() {
+        "none".to_string()
+      } else {
+        mention(&filter.denied)
+      };
+      format!("Allowed: {}\nDenied: {}", allowed, denied)
+    }
+  });
+  info!("{} /channels {} {}", command_tag(command), action, channel_id);
+
+  let chat_privacy = handler.with_user(command.user.id, |user| {
     user.with_settings(|settings| settings.chat_privacy)
   });
   let chat_privacy = chat_privacy.unwrap();
-  if let Err(err) = create_followup_message(ctx, command, message, &chat_privacy).await {
-    error!("Error sending follow-up message: {:?}", err);
+  Some(CommandResponse::new(message, chat_privacy))
+}
+
+/// Handles the `/import-personas` command
+///
+/// Imports the personas of an attached JSON file in the format of `personas.json` into the
+/// personas of the guild. Personas with the name of an existing one replace it, and with
+/// `replace` the personas of the guild are replaced altogether. The names are suggested by
+/// `persona_autocomplete`, so they show up without registering the commands again.
+///
+/// # Arguments
+///
+/// * `handler` - The Handler struct that contains the bot's state
+/// * `_ctx` - The Serenity Context for the command
+/// * `command` - The ApplicationCommandInteraction data
+///
+pub async fn import_personas_command(
+  handler: &HandlerStruct,
+  _ctx: &Context,
+  command: &ApplicationCommandInteraction,
+) -> Option<CommandResponse> {
+  let tag = command_tag(command);
+  let Some(guild_id) = command.guild_id else {
+    return Some(CommandResponse::private("Personalities can only be imported in a server."));
+  };
+  let attachment = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "file")
+    .and_then(|opt| match &opt.resolved {
+      Some(CommandDataOptionValue::Attachment(attachment)) => Some(attachment),
+      _ => None,
+    });
+  let Some(attachment) = attachment else {
+    return Some(CommandResponse::private("Attach a JSON file with the personalities to import."));
+  };
+  let replace = command
+    .data
+    .options
+    .iter()
+    .find(|opt| opt.name == "replace")
+    .and_then(|opt| opt.value.as_ref())
+    .and_then(|value| value.as_bool())
+    .unwrap_or(false);
+
+  let json = match download_text_attachment(handler, attachment).await {
+    Ok(json) => json,
+    Err(message) => return Some(CommandResponse::private(message)),
+  };
+  let (imported, skipped) = match parse_personas(&json) {
+    Ok(parsed) => parsed,
+    Err(message) => return Some(CommandResponse::private(message)),
+  };
+  if imported.is_empty() {
+    return Some(CommandResponse::private(format!(
+      "No personality was imported, {} were invalid.",
+      skipped
+    )));
   }
+  let count = imported.len();
+  handler
+    .modify_personas(guild_id, |personas| {
+      if replace {
+        personas.clear();
+      }
+      for persona in imported {
+        match personas.iter_mut().find(|other| other.name == persona.name) {
+          Some(existing) => *existing = persona,
+          None => personas.push(persona),
+        }
+      }
+    })
+    .unwrap_or_else(|err| error!("{} Error importing personalities: {:?}", tag, err));
+  info!("{} Imported {} personalities, skipped {}", tag, count, skipped);
+  Some(CommandResponse::private(format!(
+    "Imported {} personalities, skipped {} invalid ones.",
+    count, skipped
+  )))
 }
 
 pub async fn persona_control_command(
 	handler: &HandlerStruct,
 	ctx: &Context,
 	command: &ApplicationCommandInteraction,
-) {
+) -> Option<CommandResponse> {
 	let user_id = command.user.id;
 	debug!("Persona control command: {:#?}", command);
-	let name = command.data.options.get(0).unwrap().name.as_str();
+	let name = command.data.options.first().unwrap().name.as_str();
 	let mut message = Default::default();
 	match name {
 		"add" => {
-			let command_data = command.data.options.get(0).unwrap();
-			debug!("Name: {:#?}", name);
-			let name = command_data
-				.options
-				.get(0)
-				.and_then(|opt| opt.value.as_ref())
-				.and_then(|value| value.as_str())
-				.unwrap_or("");
-			debug!("Name: {:#?}", name);
-			let description = command_data
-				.options
-				.get(1)
-				.and_then(|opt| opt.value.as_ref())
-				.and_then(|value| value.as_str())
-				.unwrap_or("");
-			debug!("Description: {:#?}", description);
-			let prompt = command_data
+			// the prompt can be long and span several lines, so it is entered in a modal
+			// instead of command options. The modal is the response to the interaction.
+			// A modal has at most five fields, so the context option is passed on in its ID.
+			let custom_id = match command
+				.data
 				.options
-				.get(2)
-				.and_then(|opt| opt.value.as_ref())
-				.and_then(|value| value.as_str())
-				.unwrap_or("");
-			debug!("Prompt: {:#?}", prompt);
-
-			handler
-				.modify_personas(|personas| {
-					if let Some(personality) = personas.iter_mut().find(|p| p.name == *name) {
-						personality.prompt = prompt.to_string();
-						personality.description = description.to_string();
-						// personality.tokens = tokens;
-					} else {
-						personas.push(Personality {
-							name: name.to_string(),
-							description: description.to_string(),
-							prompt: prompt.to_string(),
-							tokens: 0,
-						});
-					}
+				.first()
+				.and_then(|option| option.options.iter().find(|option| option.name == "context"))
+				.and_then(|option| option.value.as_ref())
+				.and_then(|value| value.as_u64())
+			{
+				Some(context) => format!("persona-add:{}", context),
+				None => "persona-add".to_string(),
+			};
+			if let Err(why) = command
+				.create_interaction_response(&ctx.http, |response| {
+					response
+						.kind(InteractionResponseType::Modal)
+						.interaction_response_data(|modal| {
+							modal
+								.custom_id(custom_id)
+								.title("Add a personality")
+								.components(|components| {
+									components
+										.create_action_row(|row| {
+											row.create_input_text(|input| {
+												input
+													.custom_id("name")
+													.label("Name")
+													.style(InputTextStyle::Short)
+													.max_length(100)
+													.required(true)
+											})
+										})
+										.create_action_row(|row| {
+											row.create_input_text(|input| {
+												input
+													.custom_id("description")
+													.label("Description")
+													.style(InputTextStyle::Short)
+													.max_length(100)
+													.required(true)
+											})
+										})
+										.create_action_row(|row| {
+											row.create_input_text(|input| {
+												input
+													.custom_id("prompt")
+													.label("Prompt")
+													.style(InputTextStyle::Paragraph)
+													.required(true)
+											})
+										})
+										.create_action_row(|row| {
+											row.create_input_text(|input| {
+												input
+													.custom_id("display_name")
+													.label("Display name for webhook replies")
+													.style(InputTextStyle::Short)
+													.max_length(80)
+													.required(false)
+											})
+										})
+										.create_action_row(|row| {
+											row.create_input_text(|input| {
+												input
+													.custom_id("avatar_url")
+													.label("Avatar URL for webhook replies")
+													.style(InputTextStyle::Short)
+													.required(false)
+											})
+										})
+								})
+						})
 				})
-				.unwrap_or_else(|err| error!("Error modifying personality: {:?}", err));
-
-			message = format!(
-				"Personality {} has been created.",
-				name
-			);
-			
+				.await
+			{
+				error!("Error opening the personality modal: {:?}", why);
+			}
+			return None;
 		}
 		"remove" => {
-			let name = command.data.options.get(0).unwrap().options.get(0).unwrap();
-			debug!("Name: {:#?}", name);
-			let name = name
+			let name = command
+				.data
 				.options
-				.get(0)
-				.and_then(|opt| opt.value.as_ref())
+				.first()
+				.and_then(|option| option.options.iter().find(|option| option.name == "name"))
+				.and_then(|option| option.value.as_ref())
 				.and_then(|value| value.as_str())
-				.unwrap_or("");
-			debug!("Name: {:#?}", name);
+				.unwrap_or_default();
+			let Some(guild_id) = command.guild_id else {
+				return Some(CommandResponse::private("Personalities can only be removed in a server."));
+			};
 
+			let mut removed = false;
 			handler
-				.modify_personas(|personas| {
-					personas.retain(|p| p.name != *name);
+				.modify_personas(guild_id, |personas| {
+					let count = personas.len();
+					personas.retain(|p| p.name != name);
+					removed = personas.len() < count;
 				})
 				.unwrap_or_else(|err| error!("Error modifying personality: {:?}", err));
 
-			message = format!(
-				"Personality {} has been deleted.",
-				name
-			);
-			let command_id = handler.get_command_id("persona-control").await.unwrap();
-			// ?? remove the old command
-			let _ = ctx.http.delete_global_application_command(command_id).await;
-			// ?? create the new command
-			// let _ = register_application_commands(handler, &ctx.http).await;
+			message = if removed {
+				format!("Personality {} has been deleted.", name)
+			} else {
+				format!("There is no {} personality.", name)
+			};
 		}
 		_ => {},
 	}
-	let command_id = handler.get_command_id("personality").await.unwrap();
-	// ?? remove the old command
-	let _ = ctx.http.delete_global_application_command(command_id).await;
-	// ?? create the new command
-	let _ = register_application_commands(handler, &ctx.http).await;
-
 	let chat_privacy = handler.with_user(user_id, |user| {
 		user.with_settings(|settings| settings.chat_privacy)
 	});
 	let chat_privacy = chat_privacy.unwrap();
 
-	if let Err(err) = create_followup_message(ctx, command, message, &chat_privacy).await {
-		error!("Error sending follow-up message: {:?}", err);
-	}
+	Some(CommandResponse::new(message, chat_privacy))
 }
@@ -0,0 +1,185 @@
+//! Embeddings of the chat history, to send the AI the exchanges most relevant to a prompt
+//!
+//! - `embed`: Gets the embeddings of texts from the embeddings endpoint
+//! - `cosine_similarity`: Measures how similar two embeddings are
+//! - `most_similar`: Finds the entries of a chat history most similar to an embedding
+//! - `select_relevant_entries`: Keeps the entries of a chat history most relevant to a prompt
+//!
+//! The similarity search is a brute-force comparison with every entry, which is fast enough
+//! for the size of a channel's chat history.
+//!
+
+use serde_json::json;
+use serenity::model::id::{ChannelId, UserId};
+
+use crate::handlers::HandlerStruct;
+use crate::structures::*;
+use crate::users::{UserChannelData, UserChatHistoryEntry};
+use crate::utils::parse_api_response;
+
+/// The model used to embed the chat history and the prompts
+pub const EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// How many of the most relevant entries are sent with a prompt, besides the latest one
+pub const SEMANTIC_MEMORY_ENTRIES: usize = 4;
+
+/// Gets the embeddings of texts from the embeddings endpoint of the configured API.
+///
+/// ### Arguments
+///
+/// * `handler` - The HandlerStruct for the bot
+/// * `input` - The texts to embed
+///
+/// ### Returns
+///
+/// The embedding of each text, in the order of the input.
+///
+/// ### Errors
+///
+/// * `BotError` - The request failed or the API returned an error.
+pub async fn embed(handler: &HandlerStruct, input: Vec<String>) -> Result<Vec<Vec<f32>>, BotError> {
+	let params = EmbeddingRequestBody {
+		model: EMBEDDING_MODEL.to_string(),
+		input,
+	};
+	let url = handler.get_config().api_url("embeddings");
+	let res = handler
+		.get_api_client()
+		.post(url)
+		.body(json!(params).to_string())
+		.send()
+		.await?;
+	let mut response = parse_api_response::<EmbeddingResponseStruct>(res).await?;
+	response.data.sort_by_key(|data| data.index);
+	Ok(response.data.into_iter().map(|data| data.embedding).collect())
+}
+
+/// Returns the cosine similarity of two embeddings, `0.0` when they can't be compared.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+	if a.is_empty() || a.len() != b.len() {
+		return 0.0;
+	}
+	let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+	let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+	let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+	if norm_a == 0.0 || norm_b == 0.0 {
+		return 0.0;
+	}
+	dot / (norm_a * norm_b)
+}
+
+/// Returns the indices of the `k` entries most similar to `query`, in chronological order.
+///
+/// Entries without an embedding are never selected.
+pub fn most_similar(entries: &[UserChatHistoryEntry], query: &[f32], k: usize) -> Vec<usize> {
+	let mut scored: Vec<(usize, f32)> = entries
+		.iter()
+		.enumerate()
+		.filter_map(|(index, entry)| {
+			entry
+				.embedding
+				.as_ref()
+				.map(|embedding| (index, cosine_similarity(embedding, query)))
+		})
+		.collect();
+	scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+	let mut indices: Vec<usize> = scored.into_iter().take(k).map(|(index, _)| index).collect();
+	indices.sort_unstable();
+	indices
+}
+
+/// Keeps the entries of a chat history that are most relevant to the prompt.
+///
+/// The entries without an embedding yet are embedded together with the prompt in a single
+/// request, and their embeddings are stored in the chat history of the handler. The latest entry
+/// is always kept, so the AI can follow up on it.
+///
+/// ### Arguments
+///
+/// * `handler` - The HandlerStruct for the bot
+/// * `user_channel_key` - A tuple containing the user ID and channel ID
+/// * `channel_data` - A copy of the channel data, whose chat history is filtered
+/// * `prompt` - The user input
+///
+/// ### Errors
+///
+/// * `BotError` - The embeddings could not be retrieved, the chat history is left unchanged.
+pub async fn select_relevant_entries(
+	handler: &HandlerStruct,
+	user_channel_key: (UserId, ChannelId),
+	channel_data: &mut UserChannelData,
+	prompt: &str,
+) -> Result<(), BotError> {
+	if channel_data.chat_history.len() <= SEMANTIC_MEMORY_ENTRIES + 1 {
+		return Ok(());
+	}
+	let missing: Vec<usize> = channel_data
+		.chat_history
+		.iter()
+		.enumerate()
+		.filter(|(_, entry)| entry.embedding.is_none())
+		.map(|(index, _)| index)
+		.collect();
+	let mut input: Vec<String> = missing
+		.iter()
+		.map(|index| channel_data.chat_history[*index].message.clone())
+		.collect();
+	input.push(prompt.to_string());
+	let mut embeddings = embed(handler, input).await?;
+	let query = embeddings.pop().unwrap_or_default();
+	for (index, embedding) in missing.into_iter().zip(embeddings) {
+		channel_data.chat_history[index].embedding = Some(embedding);
+	}
+
+	// the history may have changed during the request, so the entries are matched by their time
+	let embedded = channel_data.chat_history.clone();
+	handler.modify_channel_data(user_channel_key.0, user_channel_key.1, |stored| {
+		for entry in stored.chat_history.iter_mut().filter(|entry| entry.embedding.is_none()) {
+			entry.embedding = embedded
+				.iter()
+				.find(|embedded| embedded.timestamp == entry.timestamp && embedded.message == entry.message)
+				.and_then(|embedded| embedded.embedding.clone());
+		}
+	});
+
+	let latest = channel_data.chat_history.len() - 1;
+	let mut keep = most_similar(&channel_data.chat_history[..latest], &query, SEMANTIC_MEMORY_ENTRIES);
+	keep.push(latest);
+	channel_data.chat_history = keep
+		.into_iter()
+		.map(|index| channel_data.chat_history[index].clone())
+		.collect();
+	channel_data.recount_tokens_used();
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry_with_embedding(message: &str, embedding: Option<Vec<f32>>) -> UserChatHistoryEntry {
+		let mut entry = UserChatHistoryEntry::new(message.to_string(), message.to_string(), String::new(), 1, 1, 0);
+		entry.embedding = embedding;
+		entry
+	}
+
+	#[test]
+	fn cosine_similarity_compares_directions() {
+		assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+		assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+		assert_eq!(cosine_similarity(&[1.0, 0.0], &[1.0]), 0.0);
+		assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+	}
+
+	#[test]
+	fn most_similar_keeps_the_closest_entries_in_order() {
+		let entries = vec![
+			entry_with_embedding("rust", Some(vec![1.0, 0.0])),
+			entry_with_embedding("cooking", Some(vec![0.0, 1.0])),
+			entry_with_embedding("unknown", None),
+			entry_with_embedding("cargo", Some(vec![0.9, 0.1])),
+		];
+		assert_eq!(most_similar(&entries, &[1.0, 0.0], 2), vec![0, 3]);
+		assert_eq!(most_similar(&entries, &[0.0, 1.0], 1), vec![1]);
+	}
+}
@@ -1,56 +1,395 @@
 //! Define `Handler` struct and implement the `EventHandler` trait for it
 //!
+//! ## Message handling
+//!
+//! Implement the `message` method to reply when the bot is mentioned in a message.
+//!
 //! ## Interaction handling
 //!
 //! Implement the `interaction_create` method to handle incoming interactions
 //! and delegate command handling to the appropriate functions from the `commands` module.
+//! The replies the commands return are all sent from there, see `send_command_response`.
+//!
+//! Commands work both in guild channels and in direct messages. There is no guild
+//! to look up in a DM, so everything is keyed by the user and channel IDs instead.
+//! The guild is only used to pick the default personality of new users.
 //!
 
+use chrono::{DateTime, Duration, Utc};
 use rustc_hash::FxHashMap;
-use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::VecDeque,
+  sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration as StdDuration, Instant},
+};
 
 use serenity::{
   async_trait,
   http::Http,
   model::{
-    gateway::Ready,
-    id::{UserId},
-    prelude::{interaction::Interaction, command::CommandOptionType},
+    channel::{Message, Reaction, ReactionType},
+    gateway::{Activity, Ready},
+    id::{ChannelId, CommandId, GuildId, InteractionId, MessageId, UserId},
+    webhook::Webhook,
+    prelude::{command::Command, interaction::Interaction},
   },
   prelude::{Context, EventHandler},
 };
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
 
-use crate::structures::ConfigStruct;
+use crate::structures::{ApiResponseStruct, ChannelFilter, ConfigStruct};
+use crate::backend::{ChatBackend, DryRunBackend, OpenAiBackend};
+use crate::cache::{ResponseCache, ResponseCacheKey};
+use crate::metrics::Metrics;
+use crate::tools::ToolRegistry;
 use crate::users::*;
-use crate::utils::{acknowledge_interaction, register_application_commands};
+use crate::utils::{
+  acknowledge_interaction, add_reaction_controls, build_api_client, build_reply_message, check_api_key,
+  check_chat_allowed, check_prompt_length, command_ephemeral_policy, find_command, generate_ai_response,
+  parse_personas, record_chat_history, record_reply_messages, command_tag, register_application_commands, reply_message, request_tag,
+  send_command_response, split_message, start_typing, CommandResponse, DELETE_REACTION, DISCORD_MESSAGE_LIMIT,
+  REGENERATE_REACTION,
+};
 use crate::commands::*;
 
 
+/// The part of the bot's state that is saved to the state file
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+  users: Vec<User>,
+  /// The personas every guild starts with
+  personas: Vec<Personality>,
+  #[serde(default)]
+  guild_personas: Vec<(GuildId, Vec<Personality>)>,
+  #[serde(default)]
+  shared_channels: Vec<UserChannelData>,
+  #[serde(default)]
+  guild_defaults: Vec<(GuildId, Personality)>,
+}
+
+/// The commands that chat with the AI, which only work in the channels the bot chats in
+const CHAT_COMMANDS: [&str; 4] = ["chat", "ask", "regenerate", "continue"];
+
+/// How long an interaction can be responded to, Discord invalidates its token after 15 minutes
+const INTERACTION_LIFETIME: StdDuration = StdDuration::from_secs(15 * 60);
+
+/// The replies generated for a prompt that the user still has to pick from
+#[derive(Clone, Debug)]
+pub struct PendingChoices {
+  pub user_id: UserId,
+  pub channel_id: ChannelId,
+  pub prompt: String,
+  pub response: ApiResponseStruct,
+}
+
+/// A reply the user who asked for it can regenerate or delete with a reaction, see `REACTION_CONTROLS`
+///
+/// The exchange of the reply is found in the chat history by its timestamp.
+#[derive(Clone, Debug)]
+pub struct TrackedReply {
+  pub user_id: UserId,
+  pub channel_id: ChannelId,
+  pub timestamp: DateTime<Utc>,
+}
+
+/// How many replies keep their reaction controls, the oldest ones stop reacting first
+const MAX_TRACKED_REPLIES: usize = 1000;
+
+/// The generations running for each user and channel, with the ID of the latest one
+type Generations = Arc<Mutex<FxHashMap<(UserId, ChannelId), (u64, CancellationToken)>>>;
+
+/// Keeps a generation stoppable with `/stop` until it is dropped
+///
+/// A newer generation in the same channel replaces it, so only the latest one is stopped.
+pub struct GenerationGuard {
+  generations: Generations,
+  user_channel_key: (UserId, ChannelId),
+  id: u64,
+  token: CancellationToken,
+}
+impl GenerationGuard {
+  /// Returns the token that is cancelled when the user stops the generation.
+  pub fn token(&self) -> &CancellationToken {
+    &self.token
+  }
+}
+impl Drop for GenerationGuard {
+  fn drop(&mut self) {
+    let mut generations = self.generations.lock().unwrap();
+    if generations.get(&self.user_channel_key).is_some_and(|(id, _)| *id == self.id) {
+      generations.remove(&self.user_channel_key);
+    }
+  }
+}
+
 #[derive(Clone)]
 pub struct HandlerStruct {
 	users: Arc<Mutex<FxHashMap<UserId, User>>>,
-  personas: Arc<Mutex<Vec<Personality>>>,
+  shared_channels: Arc<Mutex<FxHashMap<ChannelId, UserChannelData>>>,
+  default_personas: Arc<Mutex<Vec<Personality>>>,
+  personas: Arc<Mutex<FxHashMap<GuildId, Vec<Personality>>>>,
+  guild_defaults: Arc<Mutex<FxHashMap<GuildId, Personality>>>,
+  channel_filter: Arc<Mutex<ChannelFilter>>,
+  rate_limits: Arc<Mutex<FxHashMap<UserId, VecDeque<DateTime<Utc>>>>>,
+  cooldowns: Arc<Mutex<FxHashMap<(UserId, String), Instant>>>,
+  bot_id: Arc<Mutex<Option<UserId>>>,
+  pending_choices: Arc<Mutex<FxHashMap<MessageId, PendingChoices>>>,
+  tracked_replies: Arc<Mutex<FxHashMap<MessageId, TrackedReply>>>,
+  generations: Generations,
+  next_generation: Arc<AtomicU64>,
+  acknowledged: Arc<Mutex<FxHashMap<InteractionId, Instant>>>,
+  webhooks: Arc<Mutex<FxHashMap<ChannelId, Webhook>>>,
+  response_cache: Arc<Mutex<ResponseCache>>,
+  chat_models: Arc<Mutex<Vec<String>>>,
+  api_client: reqwest::Client,
+  http_client: reqwest::Client,
+  request_limiter: Arc<Semaphore>,
+  tools: Arc<ToolRegistry>,
+  backend: Arc<dyn ChatBackend + Send + Sync>,
+  command_ids: Arc<Mutex<Option<FxHashMap<String, CommandId>>>>,
+  metrics: Arc<Metrics>,
+  presence_started: Arc<AtomicBool>,
+  dirty: Arc<AtomicBool>,
+  shutdown: CancellationToken,
   config: Arc<ConfigStruct>,
 }
 impl HandlerStruct {
   pub fn new(config: Arc<ConfigStruct>) -> Self {
+    // one client for every request, so connections are pooled and reused
+    let api_client = build_api_client(&config).expect("Error creating the OpenAI API client");
+    // attachments are downloaded without the API key of the OpenAI client
+    let http_client = reqwest::Client::builder()
+      .timeout(StdDuration::from_secs(config.request_timeout_secs))
+      .build()
+      .expect("Error creating the HTTP client");
+    let backend: Arc<dyn ChatBackend + Send + Sync> = if config.dry_run {
+      Arc::new(DryRunBackend)
+    } else {
+      Arc::new(OpenAiBackend::new(config.clone(), api_client.clone()))
+    };
+    let permits = match config.max_concurrent_requests {
+      0 => Semaphore::MAX_PERMITS,
+      permits => permits,
+    };
     Self {
       users: Arc::new(Mutex::new(FxHashMap::default())),
-      personas: Arc::new(Mutex::new(Vec::new())),
+      shared_channels: Arc::new(Mutex::new(FxHashMap::default())),
+      default_personas: Arc::new(Mutex::new(Vec::new())),
+      personas: Arc::new(Mutex::new(FxHashMap::default())),
+      guild_defaults: Arc::new(Mutex::new(FxHashMap::default())),
+      channel_filter: Arc::new(Mutex::new(config.channel_filter.clone())),
+      rate_limits: Arc::new(Mutex::new(FxHashMap::default())),
+      cooldowns: Arc::new(Mutex::new(FxHashMap::default())),
+      bot_id: Arc::new(Mutex::new(None)),
+      pending_choices: Arc::new(Mutex::new(FxHashMap::default())),
+      tracked_replies: Arc::new(Mutex::new(FxHashMap::default())),
+      generations: Arc::new(Mutex::new(FxHashMap::default())),
+      next_generation: Arc::new(AtomicU64::new(0)),
+      acknowledged: Arc::new(Mutex::new(FxHashMap::default())),
+      webhooks: Arc::new(Mutex::new(FxHashMap::default())),
+      response_cache: Arc::new(Mutex::new(ResponseCache::new(
+        StdDuration::from_secs(config.response_cache_ttl_secs),
+        config.response_cache_size,
+      ))),
+      tools: Arc::new(ToolRegistry::default()),
+      backend,
+      api_client,
+      http_client,
+      request_limiter: Arc::new(Semaphore::new(permits)),
+      command_ids: Arc::new(Mutex::new(None)),
+      metrics: Arc::new(Metrics::new()),
+      presence_started: Arc::new(AtomicBool::new(false)),
+      dirty: Arc::new(AtomicBool::new(false)),
+      shutdown: CancellationToken::new(),
+      chat_models: Arc::new(Mutex::new(Vec::new())),
       config,
     }
   }
 
+  /// Creates a handler that sends chat requests to the given backend instead of the configured API
+  #[cfg(test)]
+  pub fn with_backend(config: Arc<ConfigStruct>, backend: Arc<dyn ChatBackend + Send + Sync>) -> Self {
+    Self {
+      backend,
+      ..Self::new(config)
+    }
+  }
+
   pub fn user_exists(&self, user_id: UserId) -> bool {
     self.users.lock().unwrap().contains_key(&user_id)
   }
 
-  pub fn add_user(&self, user_id: UserId) {
+  /// Adds a new user, starting with the default personality of the guild when it has one.
+  ///
+  /// ### Arguments
+  ///
+  /// * `user_id` - The ID of the user
+  /// * `guild_id` - The guild the user was first seen in, `None` in a DM
+  pub fn add_user(&self, user_id: UserId, guild_id: Option<GuildId>) {
+    let mut user = User::new(user_id);
+    if let Some(personality) = guild_id.and_then(|guild_id| self.get_guild_default(guild_id)) {
+      user.modify_settings(|settings| settings.set_personality(personality));
+    }
+    self.users.lock().unwrap().insert(user_id, user);
+    self.mark_dirty();
+  }
+
+  /// Returns whether the bot chats in the channel, see `ChannelFilter`.
+  pub fn channel_allowed(&self, channel_id: ChannelId) -> bool {
+    self.channel_filter.lock().unwrap().allows(channel_id.0)
+  }
+
+  /// Changes the channels the bot chats in, until the bot restarts.
+  pub fn modify_channel_filter<F, R>(&self, modify: F) -> R
+  where
+    F: FnOnce(&mut ChannelFilter) -> R,
+  {
+    modify(&mut self.channel_filter.lock().unwrap())
+  }
+
+  /// Sets the personality new users of the guild start with.
+  pub fn set_guild_default(&self, guild_id: GuildId, personality: Personality) {
+    self.guild_defaults.lock().unwrap().insert(guild_id, personality);
+    self.mark_dirty();
+  }
+
+  /// Returns the personality new users of the guild start with, if an admin set one.
+  pub fn get_guild_default(&self, guild_id: GuildId) -> Option<Personality> {
+    self.guild_defaults.lock().unwrap().get(&guild_id).cloned()
+  }
+  /// Returns the ID, total tokens and channel IDs of every user, taken under a single lock of the users.
+  ///
+  /// The channels of shared histories are those the user sent a message in.
+  pub fn usage_snapshot(&self) -> Vec<(UserId, u32, Vec<ChannelId>)> {
+    let mut shared_channels: FxHashMap<UserId, Vec<ChannelId>> = FxHashMap::default();
+    for (channel_id, channel_data) in self.shared_channels.lock().unwrap().iter() {
+      for author in channel_data.chat_history.iter().filter_map(|entry| entry.author) {
+        shared_channels.entry(author).or_default().push(*channel_id);
+      }
+    }
+    let users = self.users.lock().unwrap();
+    users
+      .values()
+      .map(|user| {
+        let mut channels: Vec<ChannelId> = user.usage.channel_history.keys().copied().collect();
+        channels.extend(shared_channels.remove(&user.id).unwrap_or_default());
+        (user.id, user.usage.total_tokens, channels)
+      })
+      .collect()
+  }
+
+  pub fn user_count(&self) -> usize {
+    self.users.lock().unwrap().len()
+  }
+
+  /// Sets the activity of the bot and, when rotation is enabled, alternates it with the number of users served.
+  ///
+  /// The rotation task is only started once, even when the bot reconnects.
+  pub async fn start_presence(&self, ctx: &Context) {
+    let activity = self.config.activity.clone();
+    ctx.set_activity(Activity::listening(&activity)).await;
+
+    let rotation = self.config.activity_rotation_secs;
+    if rotation == 0 || self.presence_started.swap(true, Ordering::SeqCst) {
+      return;
+    }
+    let handler = self.clone();
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+      let mut show_users = false;
+      loop {
+        tokio::time::sleep(StdDuration::from_secs(rotation)).await;
+        show_users = !show_users;
+        if show_users {
+          let users = handler.user_count();
+          ctx.set_activity(Activity::watching(format!("{} users", users))).await;
+        } else {
+          ctx.set_activity(Activity::listening(&activity)).await;
+        }
+      }
+    });
+  }
+
+  /// Returns a copy of the chat data of a channel as the user sees it.
+  ///
+  /// With `shared_channel_history` the data is kept per channel on the handler, so every user of
+  /// a channel sees the same history, system prompt and token count. Otherwise each user has their
+  /// own data for the channel in their `UserUsage`. The token usage and budget stay per user either way.
+  pub fn get_channel_data(&self, user_id: UserId, channel_id: ChannelId) -> Option<UserChannelData> {
+    if self.config.shared_channel_history {
+      return self.shared_channels.lock().unwrap().get(&channel_id).cloned();
+    }
+    self
+      .with_user(user_id, |user| user.with_usage(|usage| usage.channel_history.get(&channel_id).cloned()))
+      .flatten()
+  }
+
+  /// Modifies the chat data of a channel as the user sees it, creating it if needed.
+  ///
+  /// See `get_channel_data` for when the data is shared between the users of a channel. A user's
+  /// own chat data is kept for at most `max_tracked_channels` channels, dropping the least
+  /// recently used ones.
+  pub fn modify_channel_data<F, R>(&self, user_id: UserId, channel_id: ChannelId, modify: F) -> R
+  where
+    F: FnOnce(&mut UserChannelData) -> R,
+  {
+    self.mark_dirty();
+    if self.config.shared_channel_history {
+      let mut shared_channels = self.shared_channels.lock().unwrap();
+      let channel_data = shared_channels
+        .entry(channel_id)
+        .or_insert_with(|| UserChannelData::new(channel_id));
+      return modify(channel_data);
+    }
+    let mut users = self.users.lock().unwrap();
+    let user = users.entry(user_id).or_insert_with(|| User::new(user_id));
+    let channel_data = user
+      .usage
+      .channel_history
+      .entry(channel_id)
+      .or_insert_with(|| UserChannelData::new(channel_id));
+    channel_data.last_used = Utc::now();
+    let result = modify(channel_data);
+    // the channel was just used, so it is never the one dropped
+    user.usage.trim_channels(self.config.max_tracked_channels);
+    result
+  }
+
+  /// Returns a copy of the user's chat history in every channel, as sent by `/export`.
+  ///
+  /// With `shared_channel_history` only the exchanges started by the user are included.
+  pub fn export_channel_history(&self, user_id: UserId) -> FxHashMap<ChannelId, UserChannelData> {
+    if !self.config.shared_channel_history {
+      return self
+        .with_user(user_id, |user| user.with_usage(|usage| usage.channel_history.clone()))
+        .unwrap_or_default();
+    }
     self
-      .users
+      .shared_channels
       .lock()
       .unwrap()
-      .insert(user_id, User::new(user_id));
+      .iter()
+      .map(|(channel_id, channel_data)| {
+        let mut channel_data = channel_data.clone();
+        channel_data.chat_history.retain(|entry| entry.author == Some(user_id));
+        (*channel_id, channel_data)
+      })
+      .filter(|(_, channel_data)| !channel_data.chat_history.is_empty())
+      .collect()
+  }
+
+  /// Removes the user and the messages they wrote in shared channels.
+  pub fn remove_user(&self, user_id: UserId) -> bool {
+    self.mark_dirty();
+    for channel_data in self.shared_channels.lock().unwrap().values_mut() {
+      channel_data.remove_entries_by(user_id);
+    }
+    self.users.lock().unwrap().remove(&user_id).is_some()
   }
 	pub fn modify_user<F>(&self, user_id: UserId, modify: F) -> Result<(), String>
 	where
@@ -59,6 +398,7 @@ impl HandlerStruct {
 			let mut users = self.users.lock().unwrap();
 			if let Some(user) = users.get_mut(&user_id) {
 					modify(user);
+					self.mark_dirty();
 					Ok(())
 			} else {
 					Err(String::from("User not found"))
@@ -77,17 +417,19 @@ impl HandlerStruct {
 		// This is equivalent to the above
 		users.get(&user_id).map(f)
 	}
-	pub fn modify_personas<F>(&self, modify: F) -> Result<(), String>
+	/// Modifies the personas of a guild, which start as a copy of the default personas.
+	pub fn modify_personas<F>(&self, guild_id: GuildId, modify: F) -> Result<(), String>
 	where
 			F: FnOnce(&mut Vec<Personality>) + Send,
 	{
 			let mut personas = self.personas.lock().unwrap();
-			modify(&mut personas);
+			modify(personas.entry(guild_id).or_insert_with(|| self.get_default_personas()));
+			self.mark_dirty();
 			Ok(())
 	}
 	//todo: at some point, we need to make this read from a db
 	pub fn set_default_personas(&self) {
-		let mut personas = match self.personas.lock() {
+		let mut personas = match self.default_personas.lock() {
 			Ok(p) => p,
 			Err(e) => {
 					eprintln!("Error acquiring lock: {}", e);
@@ -97,22 +439,124 @@ impl HandlerStruct {
 	debug!("Setting default personas");
 	let json_contents = include_str!("personas.json");
 
-	let personas_vec: Vec<Personality> = match serde_json::from_str(&json_contents) {
-			Ok(vec) => vec,
+	let personas_vec: Vec<Personality> = match parse_personas(json_contents) {
+			Ok((vec, skipped)) => {
+					if skipped > 0 {
+							warn!("Skipped {} invalid personas", skipped);
+					}
+					vec
+			}
 			Err(e) => {
 					eprintln!("Error parsing json: {}", e);
 					return;
 			}
 	};
-	debug!("Personas: {:?}\n", personas_vec.iter().map(|p| p.name.clone()).collect::<Vec<String>>());
-	for persona in personas_vec {
-			personas.push(persona);
+	debug!(
+		"Personas: {:?}\n",
+		personas_vec
+			.iter()
+			.map(|p| format!("{} - {}", p.name, p.description))
+			.collect::<Vec<String>>()
+	);
+	// the token counts, blank names and avatars were already fixed up by `parse_personas`
+	personas.extend(personas_vec);
+
+	}
+
+	/// Saves the users and personas to the state file.
+	///
+	/// Returns the number of users that were saved.
+	pub fn save_state(&self) -> std::io::Result<usize> {
+		let users: Vec<User> = self.users.lock().unwrap().values().cloned().collect();
+		let state = SavedState {
+			users,
+			personas: self.get_default_personas(),
+			guild_personas: self
+				.personas
+				.lock()
+				.unwrap()
+				.iter()
+				.map(|(guild_id, personas)| (*guild_id, personas.clone()))
+				.collect(),
+			shared_channels: self.shared_channels.lock().unwrap().values().cloned().collect(),
+			guild_defaults: self
+				.guild_defaults
+				.lock()
+				.unwrap()
+				.iter()
+				.map(|(guild_id, personality)| (*guild_id, personality.clone()))
+				.collect(),
+		};
+		let json = serde_json::to_string(&state)?;
+		std::fs::write(&self.config.state_file, json)?;
+		Ok(state.users.len())
 	}
 
+	/// Remembers that the users or personas changed since the state was last saved.
+	fn mark_dirty(&self) {
+		self.dirty.store(true, Ordering::SeqCst);
 	}
 
-	pub fn get_personas(&self) -> Vec<Personality> {
-		match self.personas.lock() {
+	/// Saves the state like `save_state`, but only when something changed since it was last saved.
+	///
+	/// Returns the number of users saved, or `None` when nothing changed.
+	pub fn save_state_if_dirty(&self) -> std::io::Result<Option<usize>> {
+		if !self.dirty.swap(false, Ordering::SeqCst) {
+			return Ok(None);
+		}
+		self.save_state().map(Some).map_err(|why| {
+			// try again on the next flush
+			self.mark_dirty();
+			why
+		})
+	}
+
+	/// Loads the users and personas from the state file, if it exists.
+	pub fn load_state(&self) {
+		let json = match std::fs::read_to_string(&self.config.state_file) {
+			Ok(json) => json,
+			Err(e) => {
+				debug!("No state loaded from {}: {}", self.config.state_file, e);
+				return;
+			}
+		};
+		let state: SavedState = match serde_json::from_str(&json) {
+			Ok(state) => state,
+			Err(e) => {
+				error!("Error parsing state file {}: {}", self.config.state_file, e);
+				return;
+			}
+		};
+		info!(
+			"Loaded {} users and the personas of {} guilds from {}",
+			state.users.len(),
+			state.guild_personas.len(),
+			self.config.state_file
+		);
+		let mut users = self.users.lock().unwrap();
+		for user in state.users {
+			users.insert(user.id, user);
+		}
+		*self.default_personas.lock().unwrap() = state.personas;
+		self.personas.lock().unwrap().extend(state.guild_personas);
+		let mut shared_channels = self.shared_channels.lock().unwrap();
+		for channel_data in state.shared_channels {
+			shared_channels.insert(channel_data.channel_id, channel_data);
+		}
+		self.guild_defaults.lock().unwrap().extend(state.guild_defaults);
+	}
+
+	/// Returns the personas of a guild, which start as a copy of the default personas.
+	///
+	/// Each guild has its own copy, so `/persona-control` only changes the personas of its guild.
+	pub fn get_personas(&self, guild_id: GuildId) -> Vec<Personality> {
+		let mut personas = self.personas.lock().unwrap();
+		personas.entry(guild_id).or_insert_with(|| self.get_default_personas()).clone()
+	}
+
+	/// Returns the personas guilds start with, which are also the personas of DMs.
+	pub fn get_default_personas(&self) -> Vec<Personality> {
+		match self.default_personas.lock() {
 			Ok(personas) => personas.clone(),
 			Err(e) => {
 				eprintln!("Error while getting personas: {:?}", e);
@@ -120,56 +564,442 @@ impl HandlerStruct {
 			}
 		}
 	}
+
+	/// Returns the personas of the guild, or the default personas in a DM.
+	pub fn personas_for(&self, guild_id: Option<GuildId>) -> Vec<Personality> {
+		match guild_id {
+			Some(guild_id) => self.get_personas(guild_id),
+			None => self.get_default_personas(),
+		}
+	}
 	
-  pub fn get_config(&self) -> Arc<ConfigStruct> {
-    self.config.clone()
-  }
+	/// Records a chat request for the user and returns whether it is within the rate limit.
+	///
+	/// Uses a sliding window of `rate_limit_window` seconds that allows at most
+	/// `rate_limit_requests` requests. A limit of 0 disables rate limiting.
+	pub fn check_rate_limit(&self, user_id: UserId) -> bool {
+		let max_requests = self.config.rate_limit_requests as usize;
+		if max_requests == 0 {
+			return true;
+		}
+		let now = Utc::now();
+		let window_start = now - Duration::seconds(self.config.rate_limit_window as i64);
 
-	pub async fn get_command_id(&self, name: &str) -> Option<u64> {
-		let http = Arc::new(Http::new_with_application_id(
-			&self.config.discord_token,
-			self.config.app_id.parse::<u64>().unwrap(),
-		));
-		let commands = match http.get_global_application_commands().await {
-			Ok(c) => c,
-			Err(e) => {
-				error!("Error getting commands: {:?}", e);
-				return None;
+		let mut rate_limits = self.rate_limits.lock().unwrap();
+		let requests = rate_limits.entry(user_id).or_default();
+		while requests.front().is_some_and(|time| *time <= window_start) {
+			requests.pop_front();
+		}
+		if requests.len() >= max_requests {
+			return false;
+		}
+		requests.push_back(now);
+		true
+	}
+
+	/// Records a use of the command by the user, unless the command is still on cooldown.
+	///
+	/// Returns the time left until the command can be used again when it is on cooldown.
+	pub fn check_cooldown(&self, user_id: UserId, command: &str, duration: StdDuration) -> Result<(), StdDuration> {
+		let now = Instant::now();
+		let mut cooldowns = self.cooldowns.lock().unwrap();
+		let key = (user_id, command.to_string());
+		if let Some(last_use) = cooldowns.get(&key) {
+			let elapsed = now.duration_since(*last_use);
+			if elapsed < duration {
+				return Err(duration - elapsed);
 			}
-		};
-		for command in commands {
-			if command.name == name {
-				return Some(command.id.0);
+		}
+		cooldowns.insert(key, now);
+		Ok(())
+	}
+
+	pub fn add_pending_choices(&self, message_id: MessageId, pending: PendingChoices) {
+		self.pending_choices.lock().unwrap().insert(message_id, pending);
+	}
+
+	pub fn take_pending_choices(&self, message_id: MessageId) -> Option<PendingChoices> {
+		self.pending_choices.lock().unwrap().remove(&message_id)
+	}
+
+	/// Tracks a reply for its reaction controls, forgetting the oldest reply when too many are tracked.
+	pub fn track_reply(&self, message_id: MessageId, reply: TrackedReply) {
+		let mut tracked_replies = self.tracked_replies.lock().unwrap();
+		tracked_replies.insert(message_id, reply);
+		if tracked_replies.len() > MAX_TRACKED_REPLIES {
+			let oldest = tracked_replies
+				.iter()
+				.min_by_key(|(_, reply)| reply.timestamp)
+				.map(|(message_id, _)| *message_id);
+			if let Some(oldest) = oldest {
+				tracked_replies.remove(&oldest);
+			}
+		}
+	}
+
+	pub fn get_tracked_reply(&self, message_id: MessageId) -> Option<TrackedReply> {
+		self.tracked_replies.lock().unwrap().get(&message_id).cloned()
+	}
+
+	pub fn untrack_reply(&self, message_id: MessageId) {
+		self.tracked_replies.lock().unwrap().remove(&message_id);
+	}
+
+	/// Registers a generation for the user in the channel, so `/stop` can cancel it.
+	///
+	/// The generation stays registered until the returned guard is dropped.
+	pub fn start_generation(&self, user_channel_key: (UserId, ChannelId)) -> GenerationGuard {
+		let id = self.next_generation.fetch_add(1, Ordering::Relaxed);
+		let token = CancellationToken::new();
+		self
+			.generations
+			.lock()
+			.unwrap()
+			.insert(user_channel_key, (id, token.clone()));
+		GenerationGuard {
+			generations: self.generations.clone(),
+			user_channel_key,
+			id,
+			token,
+		}
+	}
+
+	/// Cancels the generation running for the user in the channel.
+	///
+	/// Returns whether there was one to cancel.
+	pub fn stop_generation(&self, user_channel_key: (UserId, ChannelId)) -> bool {
+		match self.generations.lock().unwrap().remove(&user_channel_key) {
+			Some((_, token)) => {
+				token.cancel();
+				true
 			}
+			None => false,
 		}
-		None
 	}
 
-	// we just need to add the new option to the existing command 
-	pub async fn edit_command(&self, command_id: u64, command_options: Vec<CommandOptionType>) {
-			
+	/// Remembers that Discord accepted the acknowledgement of the interaction.
+	///
+	/// Interactions that can no longer be responded to are forgotten at the same time.
+	pub fn mark_acknowledged(&self, interaction_id: InteractionId) {
+		let now = Instant::now();
+		let mut acknowledged = self.acknowledged.lock().unwrap();
+		acknowledged.retain(|_, time| now.duration_since(*time) < INTERACTION_LIFETIME);
+		acknowledged.insert(interaction_id, now);
 	}
+
+	/// Returns whether Discord accepted the acknowledgement of the interaction.
+	pub fn is_acknowledged(&self, interaction_id: InteractionId) -> bool {
+		self.acknowledged.lock().unwrap().contains_key(&interaction_id)
+	}
+
+  pub fn get_config(&self) -> Arc<ConfigStruct> {
+    self.config.clone()
+  }
+
+  /// Returns the client shared by every request to the OpenAI API, with the API key set.
+  pub fn get_api_client(&self) -> reqwest::Client {
+    self.api_client.clone()
+  }
+
+  /// Returns the client used to download attachments, which does not send the API key.
+  pub fn get_http_client(&self) -> reqwest::Client {
+    self.http_client.clone()
+  }
+
+  /// Returns the webhook the bot replies with in the channel, if it has one yet.
+  pub fn get_webhook(&self, channel_id: ChannelId) -> Option<Webhook> {
+    self.webhooks.lock().unwrap().get(&channel_id).cloned()
+  }
+
+  /// Remembers the webhook the bot replies with in the channel, or forgets it with `None`.
+  pub fn set_webhook(&self, channel_id: ChannelId, webhook: Option<Webhook>) {
+    let mut webhooks = self.webhooks.lock().unwrap();
+    match webhook {
+      Some(webhook) => webhooks.insert(channel_id, webhook),
+      None => webhooks.remove(&channel_id),
+    };
+  }
+
+  /// Returns whether the responses to one-off prompts are cached, see `RESPONSE_CACHE_TTL`.
+  pub fn response_cache_enabled(&self) -> bool {
+    self.response_cache.lock().unwrap().is_enabled()
+  }
+
+  /// Returns the cached response for the key, if it hasn't expired.
+  pub fn get_cached_response(&self, key: &ResponseCacheKey) -> Option<ApiResponseStruct> {
+    self.response_cache.lock().unwrap().get(key, Instant::now())
+  }
+
+  /// Caches the response for the key, evicting the least recently used one when the cache is full.
+  pub fn cache_response(&self, key: ResponseCacheKey, response: ApiResponseStruct) {
+    self.response_cache.lock().unwrap().insert(key, response, Instant::now());
+  }
+
+  /// Returns the chat models the API key can use, or `DEFAULT_CHAT_MODELS` until they are known.
+  pub fn get_chat_models(&self) -> Vec<String> {
+    let chat_models = self.chat_models.lock().unwrap();
+    if chat_models.is_empty() {
+      return DEFAULT_CHAT_MODELS.iter().map(|model| model.to_string()).collect();
+    }
+    chat_models.clone()
+  }
+
+  /// Replaces the chat models the API key can use, see `check_api_key`.
+  pub fn set_chat_models(&self, chat_models: Vec<String>) {
+    *self.chat_models.lock().unwrap() = chat_models;
+  }
+
+  /// Returns the token that is cancelled when the bot can't run, so `main` stops the client and exits.
+  pub fn shutdown_token(&self) -> CancellationToken {
+    self.shutdown.clone()
+  }
+
+  /// Asks `main` to stop the bot, see `shutdown_token`.
+  pub fn request_shutdown(&self) {
+    self.shutdown.cancel();
+  }
+
+  /// Returns the semaphore limiting how many chat requests are sent to the API at the same time.
+  pub fn get_request_limiter(&self) -> Arc<Semaphore> {
+    self.request_limiter.clone()
+  }
+
+  pub fn get_tools(&self) -> Arc<ToolRegistry> {
+    self.tools.clone()
+  }
+
+  pub fn get_backend(&self) -> Arc<dyn ChatBackend + Send + Sync> {
+    self.backend.clone()
+  }
+
+  pub fn get_metrics(&self) -> Arc<Metrics> {
+    self.metrics.clone()
+  }
+
+  /// Replaces the cached command IDs with the IDs of the given commands.
+  pub fn set_command_ids(&self, commands: &[Command]) {
+    let command_ids = commands
+      .iter()
+      .map(|command| (command.name.clone(), command.id))
+      .collect();
+    *self.command_ids.lock().unwrap() = Some(command_ids);
+  }
+
+  /// Returns the ID of a registered command.
+  ///
+  /// The IDs are fetched from Discord on the first call and cached until the commands are registered again.
+  pub async fn get_command_id(&self, name: &str) -> Option<CommandId> {
+    if let Some(command_ids) = self.command_ids.lock().unwrap().as_ref() {
+      return command_ids.get(name).copied();
+    }
+    let http = Http::new_with_application_id(
+      &self.config.discord_token,
+      self.config.app_id.parse::<u64>().unwrap(),
+    );
+    let commands = match self.config.dev_guild_id {
+      Some(guild_id) => http.get_guild_application_commands(guild_id).await,
+      None => http.get_global_application_commands().await,
+    };
+    match commands {
+      Ok(commands) => {
+        self.set_command_ids(&commands);
+        commands.iter().find(|command| command.name == name).map(|command| command.id)
+      }
+      Err(e) => {
+        error!("Error getting commands: {:?}", e);
+        None
+      }
+    }
+  }
 }
 
 #[async_trait]
 impl EventHandler for HandlerStruct {
-  async fn ready(&self, _: Context, ready: Ready) {
+  async fn ready(&self, ctx: Context, ready: Ready) {
     info!("{} is connected!", ready.user.name);
+    *self.bot_id.lock().unwrap() = Some(ready.user.id);
+    if let Err(why) = check_api_key(self).await {
+      error!("{}", why);
+      self.request_shutdown();
+      return;
+    }
+    self.start_presence(&ctx).await;
     let http = Arc::new(Http::new_with_application_id(
       &self.config.discord_token,
       self.config.app_id.parse::<u64>().unwrap(),
     ));
-		// set the default personas for the bot
-		self.set_default_personas();
-    if let Err(e) = register_application_commands(self, &http,).await {
+		// set the default personas for the bot, unless they were loaded from the state file
+		// or this is a reconnect
+		if self.get_default_personas().is_empty() {
+			self.set_default_personas();
+		}
+    if let Err(e) = register_application_commands(self, &http).await {
       error!("Error registering application commands: {:?}", e);
     }
   }
 
+  ///
+  /// Handles message events
+  ///
+  /// Replies to messages that mention the bot, using the rest of the message as the prompt.
+  /// The reply goes through the same checks and chat history as the `/chat` command.
+  ///
+  /// # Arguments
+  ///
+  /// * `ctx` - The Serenity Context for the event
+  /// * `msg` - The Message data
+  ///
+  async fn message(&self, ctx: Context, msg: Message) {
+    // never reply to bots, including ourselves, to avoid reply loops
+    if msg.author.bot {
+      return;
+    }
+    let bot_id = match *self.bot_id.lock().unwrap() {
+      Some(bot_id) => bot_id,
+      None => return,
+    };
+    if !msg.mentions_user_id(bot_id) {
+      return;
+    }
+    // like `/chat`, mentions are only answered in the allowed channels
+    if !self.channel_allowed(msg.channel_id) {
+      debug!("[channel:{}] Mentions are not answered in this channel", msg.channel_id);
+      return;
+    }
+
+    let prompt = msg
+      .content
+      .replace(&format!("<@{}>", bot_id), "")
+      .replace(&format!("<@!{}>", bot_id), "");
+    let prompt = prompt.trim();
+    if prompt.is_empty() {
+      return;
+    }
+
+    let user_id = msg.author.id;
+    let channel_id = msg.channel_id;
+    let tag = request_tag(msg.id.0, user_id, channel_id);
+    if !self.user_exists(user_id) {
+      self.add_user(user_id, msg.guild_id);
+    }
+    info!(
+      "{} User {}#{} (mention): {}",
+      tag, msg.author.name, msg.author.discriminator, prompt
+    );
+    self.metrics.chat_requests.inc();
+
+    // a mention is a `/chat`, so it can't be used to get around the cooldown of the command
+    if let Some(seconds) = self.config.command_cooldowns.get("chat") {
+      if let Err(remaining) = self.check_cooldown(user_id, "chat", StdDuration::from_secs(*seconds)) {
+        let message = format!("/chat is on cooldown, try again in {}s.", remaining.as_secs() + 1);
+        if let Err(why) = msg.reply(&ctx.http, message).await {
+          error!("{} Error replying to message: {:?}", tag, why);
+        }
+        return;
+      }
+    }
+    if let Err(message) = check_prompt_length(&self.config, prompt) {
+      info!("{} Refused prompt from {}#{}: {}", tag, msg.author.name, msg.author.discriminator, message);
+      if let Err(why) = msg.reply(&ctx.http, message).await {
+        error!("{} Error replying to message: {:?}", tag, why);
+      }
+      return;
+    }
+    if let Err(message) = check_chat_allowed(self, user_id, prompt).await {
+      if let Err(why) = msg.reply(&ctx.http, message).await {
+        error!("{} Error replying to message: {:?}", tag, why);
+      }
+      return;
+    }
+
+    let typing = start_typing(ctx.http.clone(), channel_id);
+    let response = generate_ai_response(self, prompt, (user_id, channel_id)).await;
+    drop(typing);
+    let response = match response {
+      Ok(response) => response,
+      Err(e) => {
+        error!("{} Error generating response: {}", tag, e);
+        if let Err(why) = msg.reply(&ctx.http, e.user_message()).await {
+          error!("{} Error replying to message: {:?}", tag, why);
+        }
+        return;
+      }
+    };
+    let content = reply_message(self, user_id, &response);
+    let mut chunks = split_message(&content, DISCORD_MESSAGE_LIMIT).into_iter();
+
+    let first = chunks.next().unwrap_or_default();
+    let reply = match msg
+      .channel_id
+      .send_message(&ctx.http, |message| build_reply_message(message, first, None).reference_message(&msg))
+      .await
+    {
+      Ok(reply) => reply,
+      Err(why) => {
+        error!("{} Error replying to message: {:?}", tag, why);
+        return;
+      }
+    };
+    let mut reply_message_ids = vec![reply.id];
+    for chunk in chunks {
+      match msg
+        .channel_id
+        .send_message(&ctx.http, |message| build_reply_message(message, chunk, None))
+        .await
+      {
+        Ok(message) => reply_message_ids.push(message.id),
+        // the tokens were spent either way, so the exchange is still recorded
+        Err(why) => {
+          error!("{} Error sending the rest of the reply: {:?}", tag, why);
+          break;
+        }
+      }
+    }
+
+    record_chat_history(self, user_id, channel_id, prompt, &response);
+    record_reply_messages(self, user_id, channel_id, reply_message_ids);
+    add_reaction_controls(self, &ctx, channel_id, reply.id, user_id).await;
+  }
+
+  ///
+  /// Handles reactions added to messages
+  ///
+  /// Regenerates or deletes a reply with reaction controls when the user who asked for it reacts
+  /// with `REGENERATE_REACTION` or `DELETE_REACTION`. Reactions of anyone else are ignored.
+  ///
+  /// # Arguments
+  ///
+  /// * `ctx` - The Serenity Context for the event
+  /// * `reaction` - The Reaction data
+  ///
+  async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+    let Some(user_id) = reaction.user_id else {
+      return;
+    };
+    // the bot adds the controls itself
+    if Some(user_id) == *self.bot_id.lock().unwrap() {
+      return;
+    }
+    let Some(reply) = self.get_tracked_reply(reaction.message_id) else {
+      return;
+    };
+    if reply.user_id != user_id {
+      return;
+    }
+    match &reaction.emoji {
+      ReactionType::Unicode(emoji) if emoji == REGENERATE_REACTION => {
+        regenerate_reaction(self, &ctx, &reaction, reply).await
+      }
+      ReactionType::Unicode(emoji) if emoji == DELETE_REACTION => delete_reaction(self, &ctx, &reaction, reply).await,
+      _ => {}
+    }
+  }
+
   ///
   /// Handles interaction events
   ///
-  /// Processes the user's interaction with the bot and executes the corresponding command.
+  /// Processes the user's interaction with the bot and executes the corresponding command,
+  /// message component or modal.
   ///
   /// # Arguments
   ///
@@ -177,37 +1007,100 @@ impl EventHandler for HandlerStruct {
   /// * `interaction` - The Interaction data
   ///
   async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
-    if let Interaction::ApplicationCommand(command) = interaction {
-      let user_id = command.user.id;
-      if !self.user_exists(user_id) {
-        self.add_user(user_id);
-      }
+    match interaction {
+      Interaction::ApplicationCommand(command) => {
+        let user_id = command.user.id;
+        let tag = command_tag(&command);
+        if command.guild_id.is_none() {
+          debug!("{} Received /{} in a direct message", tag, command.data.name);
+        }
+        if !self.user_exists(user_id) {
+          self.add_user(user_id, command.guild_id);
+        }
+        // reply in the language of the user's Discord client until they choose one with /config
+        let _ = self.modify_user(user_id, |user| {
+          user.modify_settings(|settings| {
+            if settings.get_locale().is_none() {
+              settings.set_locale(Some(command.locale.clone()));
+            }
+          })
+        });
+
+        let total_tokens = self.with_user(user_id, |user| user.with_usage(|usage| usage.get_total_tokens())).unwrap();
+        debug!("{} Total tokens: {}", tag, total_tokens);
+        let chat_privacy = self.with_user(user_id, |user| user.with_settings(|settings| settings.get_chat_privacy())).unwrap();
+        let definition = find_command(&command.data.name);
+        let ephemeral = command_ephemeral_policy(&self.config, &command.data.name).is_ephemeral(chat_privacy);
+
+        // opening a modal has to be the first response, so it can't be acknowledged first
+        let opens_modal = command.data.name == "persona-control"
+          && command.data.options.first().map(|option| option.name.as_str()) == Some("add");
+        if !opens_modal {
+          if let Err(why) = acknowledge_interaction(self, &command, &ctx, ephemeral).await {
+            // the interaction token is unusable without an acknowledgement, so the command still
+            // runs and its reply is posted in the channel, see `post_reply_in_channel`
+            error!("{} {}, the reply will be posted without the interaction", tag, why);
+          }
+        }
+
+        if CHAT_COMMANDS.contains(&command.data.name.as_str()) && !self.channel_allowed(command.channel_id) {
+          debug!("{} /{} is not allowed in this channel", tag, command.data.name);
+          let response = CommandResponse::private("I'm not available in this channel.");
+          let _ = send_command_response(self, &ctx, &command, response, ephemeral).await;
+          return;
+        }
+
+        if let Some(seconds) = self.config.command_cooldowns.get(&command.data.name) {
+          let cooldown = StdDuration::from_secs(*seconds);
+          if let Err(remaining) = self.check_cooldown(user_id, &command.data.name, cooldown) {
+            let message = format!(
+              "/{} is on cooldown, try again in {}s.",
+              command.data.name,
+              remaining.as_secs() + 1
+            );
+            // the cooldown message is only for the user
+            let _ = send_command_response(self, &ctx, &command, CommandResponse::private(message), ephemeral).await;
+            return;
+          }
+        }
 
-			let total_tokens = self.with_user(user_id, |user| user.with_usage(|usage| usage.get_total_tokens())).unwrap();
-			debug!("Total tokens: {}", total_tokens);
-			let chat_privacy = self.with_user(user_id, |user| user.with_settings(|settings| settings.get_chat_privacy())).unwrap();
-			let ephemeral = match command.data.name.as_str() {
-				"private" | "public" => true,
-				_ => chat_privacy
-				//  chat_privacy == ChatPrivacy::Private
-			};
-					
-			acknowledge_interaction(&command, &ctx, ephemeral).await;
-
-      match command.data.name.as_str() {
-        "chat" => chat_command(self, &ctx, &command).await,
-        "prompt" => {
-          todo!()
+        let response = match definition {
+          Some(definition) => (definition.run)(self, &ctx, &command).await,
+          None => {
+            error!("{} Unknown command: {}", tag, command.data.name);
+            None
+          }
+        };
+        // the reply of every command is sent here, with its privacy and split into messages
+        if let Some(response) = response {
+          if send_command_response(self, &ctx, &command, response, ephemeral).await.is_err() {
+            error!("{} Error sending the reply to /{}", tag, command.data.name);
+          }
         }
-        "personality" => personality_command(self, &ctx, &command).await,
-        "reset" => reset_command(self, &ctx, &command).await,
-        "private" => private_command(self, &ctx, &command).await,
-        "public" => public_command(self, &ctx, &command).await,
-				"persona-control" => persona_control_command(self, &ctx, &command).await,
-        _ => {
-          error!("Unknown command: {}", command.data.name);
+      }
+      Interaction::MessageComponent(component) => {
+        match component.data.custom_id.as_str() {
+          "choice" => choice_component(self, &ctx, &component).await,
+          "forget-confirm" | "forget-cancel" => forget_component(self, &ctx, &component).await,
+          id if id.starts_with("history:") => history_component(self, &ctx, &component).await,
+          _ => {
+            error!("Unknown component: {}", component.data.custom_id);
+          }
         }
-      }	
+      }
+      Interaction::ModalSubmit(modal) => {
+        match modal.data.custom_id.as_str() {
+          id if id.starts_with("persona-add") => persona_add_modal(self, &ctx, &modal).await,
+          _ => {
+            error!("Unknown modal: {}", modal.data.custom_id);
+          }
+        }
+      }
+      Interaction::Autocomplete(autocomplete) => match autocomplete.data.name.as_str() {
+        "model" => model_autocomplete(self, &ctx, &autocomplete).await,
+        _ => persona_autocomplete(self, &ctx, &autocomplete).await,
+      },
+      _ => {}
     }
   }
 }
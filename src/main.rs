@@ -10,17 +10,23 @@ use clap::{Arg, Command};
 
 use serenity::prelude::GatewayIntents;
 
+mod backend;
+mod cache;
 mod commands;
+mod embeddings;
 mod handlers;
+mod metrics;
 mod structures;
+mod tools;
 mod utils;
 mod users;
 
 use dotenvy::dotenv;
 
 use crate::handlers::{HandlerStruct};
-use crate::utils::get_env_var;
-use crate::structures::{Config, ConfigStruct};
+use crate::utils::{get_env_var, parse_channel_ids, parse_cooldowns, parse_ephemeral_policies, parse_flag, parse_number};
+use crate::structures::{ChannelFilter, Config, ConfigError, ConfigStruct, DEFAULT_API_BASE_URL};
+use crate::users::Model;
 
 extern crate sensible_env_logger;
 #[macro_use]
@@ -75,15 +81,433 @@ let matches = Command::new("RustGPT-Discord Bot")
 		.help("Sets the global logs for the app")
 		.default_value("off")
 	)
+	.arg(
+		Arg::new("openai_moderation")
+		.short('m')
+		.long("openai-moderation")
+		.value_name("OPENAI_MODERATION")
+		.help("Checks prompts against the OpenAI moderation endpoint before sending them")
+		.default_value("false"),
+	)
+	.arg(
+		Arg::new("openai_api_base_url")
+		.short('b')
+		.long("openai-api-base-url")
+		.value_name("OPENAI_API_BASE_URL")
+		.help("Sets the base URL of an OpenAI compatible API")
+		.default_value(DEFAULT_API_BASE_URL),
+	)
+	.arg(
+		Arg::new("dry_run")
+		.long("dry-run")
+		.value_name("DRY_RUN")
+		.help("Echoes prompts back instead of sending them to the API, so no API key or tokens are needed")
+		.default_value("false"),
+	)
+	.arg(
+		Arg::new("openai_org")
+		.long("openai-org")
+		.value_name("OPENAI_ORG")
+		.help("Sets the OpenAI organization the requests are billed to"),
+	)
+	.arg(
+		Arg::new("openai_auth_scheme")
+		.long("openai-auth-scheme")
+		.value_name("OPENAI_AUTH_SCHEME")
+		.help("Sets how the API key is sent, either 'bearer' or 'api-key' (Azure)")
+		.default_value("bearer"),
+	)
+	.arg(
+		Arg::new("rate_limit_requests")
+		.long("rate-limit-requests")
+		.value_name("RATE_LIMIT_REQUESTS")
+		.help("Sets how many chat requests a user can make per rate limit window, 0 disables the limit")
+		.default_value("10"),
+	)
+	.arg(
+		Arg::new("rate_limit_window")
+		.long("rate-limit-window")
+		.value_name("RATE_LIMIT_WINDOW")
+		.help("Sets the length of the rate limit window in seconds")
+		.default_value("60"),
+	)
+	.arg(
+		Arg::new("state_file")
+		.long("state-file")
+		.value_name("STATE_FILE")
+		.help("Sets the file the users and personas are saved to on shutdown")
+		.default_value("state.json"),
+	)
+	.arg(
+		Arg::new("ack_timeout")
+		.long("ack-timeout")
+		.value_name("ACK_TIMEOUT_MS")
+		.help("Sets how many milliseconds to wait for Discord to accept the acknowledgement of a command")
+		.default_value("2000"),
+	)
+	.arg(
+		Arg::new("dev_guild_id")
+		.long("dev-guild-id")
+		.value_name("DEV_GUILD_ID")
+		.help("Registers the commands to this guild only, for development"),
+	)
+	.arg(
+		Arg::new("command_cooldowns")
+		.long("command-cooldowns")
+		.value_name("COMMAND_COOLDOWNS")
+		.help("Sets the cooldown of commands in seconds, as comma separated command=seconds pairs")
+		.default_value("imagine=30"),
+	)
+	.arg(
+		Arg::new("command_ephemeral")
+		.long("command-ephemeral")
+		.value_name("COMMAND_EPHEMERAL")
+		.help("Sets who sees the replies of commands, as comma separated command=always|never|privacy pairs")
+		.default_value(""),
+	)
+	.arg(
+		Arg::new("metrics_addr")
+		.long("metrics-addr")
+		.value_name("METRICS_ADDR")
+		.help("Serves Prometheus metrics and a health check at this address"),
+	)
+	.arg(
+		Arg::new("bot_activity")
+		.long("bot-activity")
+		.value_name("BOT_ACTIVITY")
+		.help("Sets what the bot is shown listening to")
+		.default_value("/chat"),
+	)
+	.arg(
+		Arg::new("bot_activity_rotation")
+		.long("bot-activity-rotation")
+		.value_name("BOT_ACTIVITY_ROTATION")
+		.help("Sets how many seconds the activity alternates with the number of users served, 0 disables the rotation")
+		.default_value("300"),
+	)
+	.arg(
+		Arg::new("shared_channel_history")
+		.long("shared-channel-history")
+		.value_name("SHARED_CHANNEL_HISTORY")
+		.help("Shares the chat history of a channel between all of its users instead of keeping one per user")
+		.default_value("false"),
+	)
+	.arg(
+		Arg::new("request_timeout")
+		.long("request-timeout")
+		.value_name("OPENAI_REQUEST_TIMEOUT")
+		.help("Sets how many seconds to wait for an answer of the OpenAI API")
+		.default_value("60"),
+	)
+	.arg(
+		Arg::new("allowed_channels")
+		.long("allowed-channels")
+		.value_name("ALLOWED_CHANNELS")
+		.help("Confines the chat commands to these channels, as comma separated channel IDs"),
+	)
+	.arg(
+		Arg::new("denied_channels")
+		.long("denied-channels")
+		.value_name("DENIED_CHANNELS")
+		.help("Disables the chat commands in these channels, as comma separated channel IDs"),
+	)
+	.arg(
+		Arg::new("max_prompt_chars")
+		.long("max-prompt-chars")
+		.value_name("MAX_PROMPT_CHARS")
+		.help("Sets how many characters a chat prompt can have")
+		.default_value("4000"),
+	)
+	.arg(
+		Arg::new("semantic_memory")
+		.long("semantic-memory")
+		.value_name("SEMANTIC_MEMORY")
+		.help("Sends the most relevant exchanges of the chat history with a prompt instead of the latest ones")
+		.default_value("false"),
+	)
+	.arg(
+		Arg::new("max_concurrent_requests")
+		.long("max-concurrent-requests")
+		.value_name("MAX_CONCURRENT_REQUESTS")
+		.help("Sets how many chat requests are sent to the API at the same time, 0 for no limit")
+		.default_value("8"),
+	)
+	.arg(
+		Arg::new("state_flush_interval")
+		.long("state-flush-interval")
+		.value_name("STATE_FLUSH_INTERVAL")
+		.help("Sets every how many seconds changed state is saved to the state file, 0 only saves on Ctrl-C")
+		.default_value("60"),
+	)
+	.arg(
+		Arg::new("max_tracked_channels")
+		.long("max-tracked-channels")
+		.value_name("MAX_TRACKED_CHANNELS")
+		.help("Sets in how many channels the chat history of a user is kept, 0 for no limit")
+		.default_value("50"),
+	)
+	.arg(
+		Arg::new("use_webhook")
+		.long("use-webhook")
+		.value_name("USE_WEBHOOK")
+		.help("Posts public /chat replies through a webhook of the channel instead of the interaction response")
+		.default_value("false"),
+	)
+	.arg(
+		Arg::new("reaction_controls")
+		.long("reaction-controls")
+		.value_name("REACTION_CONTROLS")
+		.help("Adds reactions to regenerate or delete public replies")
+		.default_value("false"),
+	)
+	.arg(
+		Arg::new("log_prompts")
+		.long("log-prompts")
+		.value_name("LOG_PROMPTS")
+		.help("Logs the full bodies of the chat requests and responses at debug level")
+		.default_value("false"),
+	)
+	.arg(
+		Arg::new("response_cache_ttl")
+		.long("response-cache-ttl")
+		.value_name("RESPONSE_CACHE_TTL")
+		.help("Sets how many seconds the replies to /ask are cached for identical prompts, 0 disables the cache")
+		.default_value("0"),
+	)
+	.arg(
+		Arg::new("response_cache_size")
+		.long("response-cache-size")
+		.value_name("RESPONSE_CACHE_SIZE")
+		.help("Sets how many replies are cached at most")
+		.default_value("100"),
+	)
+	.arg(
+		Arg::new("refuse_invalid_api_key")
+		.long("refuse-invalid-api-key")
+		.value_name("REFUSE_INVALID_API_KEY")
+		.help("Stops the bot when the API key is rejected at startup, instead of only logging an error")
+		.default_value("false"),
+	)
+	.arg(
+		Arg::new("fallback_model")
+		.long("fallback-model")
+		.value_name("FALLBACK_MODEL")
+		.help("Sets the model that answers when the user's model is rate limited or overloaded"),
+	)
+	.arg(
+		Arg::new("empty_reply_message")
+		.long("empty-reply-message")
+		.value_name("EMPTY_REPLY_MESSAGE")
+		.help("Sets the reply sent when the AI answers with an empty message")
+		.default_value("I don't have a response for that."),
+	)
+	.arg(
+		Arg::new("global_system_prefix")
+		.long("global-system-prefix")
+		.value_name("GLOBAL_SYSTEM_PREFIX")
+		.help("Adds these instructions before the system prompt of every personality"),
+	)
 	.get_matches();
- //todo: rework this
-	let api_key = get_env_var("OPENAI_API_KEY", "openai_api_key", Some(&matches));
+	let dry_run = get_env_var("DRY_RUN", "dry_run", Some(&matches));
+	// nothing is sent to the API in a dry run, so the key is optional
+	let api_key = match get_env_var("OPENAI_API_KEY", "openai_api_key", Some(&matches)) {
+		Err(ConfigError::Missing(_)) if dry_run.as_deref().is_ok_and(parse_flag) => Ok(String::new()),
+		api_key => api_key,
+	};
 	let discord_token = get_env_var("DISCORD_TOKEN", "discord_token", Some(&matches));
 	let app_id = get_env_var("DISCORD_APP_ID", "discord_app_id", Some(&matches));
 	let rust_log = get_env_var("RUST_LOG", "rust_log", Some(&matches));
 	let global_logs = get_env_var("GLOBAL_LOG_LEVEL", "global_log_level", Some(&matches));
-	
-	let config: ConfigStruct = Config::new(api_key, discord_token, app_id, rust_log, global_logs);
+	let moderation = get_env_var("OPENAI_MODERATION", "openai_moderation", Some(&matches));
+	let api_base_url = get_env_var("OPENAI_API_BASE_URL", "openai_api_base_url", Some(&matches));
+	let api_auth_scheme = get_env_var("OPENAI_AUTH_SCHEME", "openai_auth_scheme", Some(&matches));
+	let openai_org = match get_env_var("OPENAI_ORG", "openai_org", Some(&matches)) {
+		Ok(value) if value.trim().is_empty() => Ok(None),
+		Ok(value) => Ok(Some(value.trim().to_string())),
+		Err(ConfigError::Missing(_)) => Ok(None),
+		Err(e) => Err(e),
+	};
+	let rate_limit_requests = get_env_var("RATE_LIMIT_REQUESTS", "rate_limit_requests", Some(&matches))
+		.and_then(|value| parse_number("RATE_LIMIT_REQUESTS", &value));
+	let rate_limit_window = get_env_var("RATE_LIMIT_WINDOW", "rate_limit_window", Some(&matches))
+		.and_then(|value| parse_number("RATE_LIMIT_WINDOW", &value));
+	let state_file = get_env_var("STATE_FILE", "state_file", Some(&matches));
+	let ack_timeout = get_env_var("ACK_TIMEOUT_MS", "ack_timeout", Some(&matches))
+		.and_then(|value| parse_number("ACK_TIMEOUT_MS", &value));
+	let command_cooldowns = get_env_var("COMMAND_COOLDOWNS", "command_cooldowns", Some(&matches))
+		.and_then(|value| parse_cooldowns("COMMAND_COOLDOWNS", &value));
+	let command_ephemeral = get_env_var("COMMAND_EPHEMERAL", "command_ephemeral", Some(&matches))
+		.and_then(|value| parse_ephemeral_policies("COMMAND_EPHEMERAL", &value));
+	let activity = get_env_var("BOT_ACTIVITY", "bot_activity", Some(&matches));
+	let activity_rotation = get_env_var("BOT_ACTIVITY_ROTATION", "bot_activity_rotation", Some(&matches))
+		.and_then(|value| parse_number("BOT_ACTIVITY_ROTATION", &value));
+	let shared_channel_history = get_env_var("SHARED_CHANNEL_HISTORY", "shared_channel_history", Some(&matches));
+	let global_system_prefix = match get_env_var("GLOBAL_SYSTEM_PREFIX", "global_system_prefix", Some(&matches)) {
+		Ok(value) if value.trim().is_empty() => Ok(None),
+		Ok(value) => Ok(Some(value)),
+		Err(ConfigError::Missing(_)) => Ok(None),
+		Err(e) => Err(e),
+	};
+	// Discord refuses empty messages, so a blank reply is an error
+	let empty_reply_message = get_env_var("EMPTY_REPLY_MESSAGE", "empty_reply_message", Some(&matches))
+		.and_then(|value| match value.trim() {
+			"" => Err(ConfigError::Invalid("EMPTY_REPLY_MESSAGE".to_string(), value)),
+			_ => Ok(value),
+		});
+	let use_webhook = get_env_var("USE_WEBHOOK", "use_webhook", Some(&matches));
+	let reaction_controls = get_env_var("REACTION_CONTROLS", "reaction_controls", Some(&matches));
+	let log_prompts = get_env_var("LOG_PROMPTS", "log_prompts", Some(&matches));
+	let refuse_invalid_api_key = get_env_var("REFUSE_INVALID_API_KEY", "refuse_invalid_api_key", Some(&matches));
+	let fallback_model = match get_env_var("FALLBACK_MODEL", "fallback_model", Some(&matches)) {
+		Ok(value) if value.trim().is_empty() => Ok(None),
+		Ok(value) => Ok(Some(Model::from_name(value.trim()))),
+		Err(ConfigError::Missing(_)) => Ok(None),
+		Err(e) => Err(e),
+	};
+	let semantic_memory = get_env_var("SEMANTIC_MEMORY", "semantic_memory", Some(&matches));
+	let max_prompt_chars = get_env_var("MAX_PROMPT_CHARS", "max_prompt_chars", Some(&matches))
+		.and_then(|value| parse_number("MAX_PROMPT_CHARS", &value));
+	// optional, so a missing list just means no channel is allowed or denied explicitly
+	let allowed_channels = match get_env_var("ALLOWED_CHANNELS", "allowed_channels", Some(&matches)) {
+		Ok(value) => parse_channel_ids("ALLOWED_CHANNELS", &value),
+		Err(ConfigError::Missing(_)) => Ok(Vec::new()),
+		Err(e) => Err(e),
+	};
+	let denied_channels = match get_env_var("DENIED_CHANNELS", "denied_channels", Some(&matches)) {
+		Ok(value) => parse_channel_ids("DENIED_CHANNELS", &value),
+		Err(ConfigError::Missing(_)) => Ok(Vec::new()),
+		Err(e) => Err(e),
+	};
+	let max_concurrent_requests = get_env_var("MAX_CONCURRENT_REQUESTS", "max_concurrent_requests", Some(&matches))
+		.and_then(|value| parse_number("MAX_CONCURRENT_REQUESTS", &value));
+	let state_flush_interval = get_env_var("STATE_FLUSH_INTERVAL", "state_flush_interval", Some(&matches))
+		.and_then(|value| parse_number("STATE_FLUSH_INTERVAL", &value));
+	let max_tracked_channels = get_env_var("MAX_TRACKED_CHANNELS", "max_tracked_channels", Some(&matches))
+		.and_then(|value| parse_number("MAX_TRACKED_CHANNELS", &value));
+	let response_cache_ttl = get_env_var("RESPONSE_CACHE_TTL", "response_cache_ttl", Some(&matches))
+		.and_then(|value| parse_number("RESPONSE_CACHE_TTL", &value));
+	let response_cache_size = get_env_var("RESPONSE_CACHE_SIZE", "response_cache_size", Some(&matches))
+		.and_then(|value| parse_number("RESPONSE_CACHE_SIZE", &value));
+	let request_timeout = get_env_var("OPENAI_REQUEST_TIMEOUT", "request_timeout", Some(&matches))
+		.and_then(|value| parse_number("OPENAI_REQUEST_TIMEOUT", &value));
+	// optional, so a missing value just means the commands are registered globally
+	let dev_guild_id = match get_env_var("DEV_GUILD_ID", "dev_guild_id", Some(&matches)) {
+		Ok(value) => parse_number("DEV_GUILD_ID", &value).map(Some),
+		Err(ConfigError::Missing(_)) => Ok(None),
+		Err(e) => Err(e),
+	};
+	let metrics_addr = match get_env_var("METRICS_ADDR", "metrics_addr", Some(&matches)) {
+		Ok(value) => Ok(Some(value)),
+		Err(ConfigError::Missing(_)) => Ok(None),
+		Err(e) => Err(e),
+	};
+
+	// report every missing or invalid variable at once instead of one per run
+	let errors: Vec<&ConfigError> = [
+		api_key.as_ref().err(),
+		discord_token.as_ref().err(),
+		app_id.as_ref().err(),
+		rust_log.as_ref().err(),
+		global_logs.as_ref().err(),
+		moderation.as_ref().err(),
+		api_base_url.as_ref().err(),
+		api_auth_scheme.as_ref().err(),
+		openai_org.as_ref().err(),
+		dry_run.as_ref().err(),
+		rate_limit_requests.as_ref().err(),
+		rate_limit_window.as_ref().err(),
+		state_file.as_ref().err(),
+		ack_timeout.as_ref().err(),
+		dev_guild_id.as_ref().err(),
+		command_cooldowns.as_ref().err(),
+		command_ephemeral.as_ref().err(),
+		metrics_addr.as_ref().err(),
+		activity.as_ref().err(),
+		activity_rotation.as_ref().err(),
+		shared_channel_history.as_ref().err(),
+		request_timeout.as_ref().err(),
+		max_concurrent_requests.as_ref().err(),
+		allowed_channels.as_ref().err(),
+		denied_channels.as_ref().err(),
+		max_prompt_chars.as_ref().err(),
+		semantic_memory.as_ref().err(),
+		global_system_prefix.as_ref().err(),
+		empty_reply_message.as_ref().err(),
+		use_webhook.as_ref().err(),
+		reaction_controls.as_ref().err(),
+		response_cache_ttl.as_ref().err(),
+		response_cache_size.as_ref().err(),
+		log_prompts.as_ref().err(),
+		fallback_model.as_ref().err(),
+		refuse_invalid_api_key.as_ref().err(),
+		max_tracked_channels.as_ref().err(),
+		state_flush_interval.as_ref().err(),
+	]
+		.into_iter()
+		.flatten()
+		.collect();
+	if !errors.is_empty() {
+		let missing: Vec<&str> = errors
+			.iter()
+			.filter_map(|e| match e {
+				ConfigError::Missing(var_name) => Some(var_name.as_str()),
+				_ => None,
+			})
+			.collect();
+		if !missing.is_empty() {
+			eprintln!(
+				"missing {} - set them with command-line arguments, environment variables, or the dotenv file.",
+				missing.join(", ")
+			);
+		}
+		for e in errors.iter().filter(|e| matches!(e, ConfigError::Invalid(..))) {
+			eprintln!("{}", e);
+		}
+		std::process::exit(1);
+	}
+
+	let mut config: ConfigStruct = Config::new(
+		api_key.unwrap(),
+		discord_token.unwrap(),
+		app_id.unwrap(),
+		rust_log.unwrap(),
+		global_logs.unwrap(),
+	);
+	config.moderation = parse_flag(&moderation.unwrap());
+	config.api_base_url = api_base_url.unwrap();
+	config.api_auth_scheme = api_auth_scheme.unwrap().to_lowercase();
+	config.openai_org = openai_org.unwrap();
+	config.dry_run = parse_flag(&dry_run.unwrap());
+	config.rate_limit_requests = rate_limit_requests.unwrap();
+	config.rate_limit_window = rate_limit_window.unwrap();
+	config.state_file = state_file.unwrap();
+	config.ack_timeout_ms = ack_timeout.unwrap();
+	config.dev_guild_id = dev_guild_id.unwrap();
+	config.command_cooldowns = command_cooldowns.unwrap();
+	config.command_ephemeral = command_ephemeral.unwrap();
+	config.metrics_addr = metrics_addr.unwrap();
+	config.activity = activity.unwrap();
+	config.activity_rotation_secs = activity_rotation.unwrap();
+	config.shared_channel_history = parse_flag(&shared_channel_history.unwrap());
+	config.request_timeout_secs = request_timeout.unwrap();
+	config.max_concurrent_requests = max_concurrent_requests.unwrap();
+	config.channel_filter = ChannelFilter {
+		allowed: allowed_channels.unwrap(),
+		denied: denied_channels.unwrap(),
+	};
+	config.max_prompt_chars = max_prompt_chars.unwrap();
+	config.semantic_memory = parse_flag(&semantic_memory.unwrap());
+	config.global_system_prefix = global_system_prefix.unwrap();
+	config.empty_reply_message = empty_reply_message.unwrap();
+	config.use_webhook = parse_flag(&use_webhook.unwrap());
+	config.reaction_controls = parse_flag(&reaction_controls.unwrap());
+	config.response_cache_ttl_secs = response_cache_ttl.unwrap();
+	config.response_cache_size = response_cache_size.unwrap();
+	config.log_prompts = parse_flag(&log_prompts.unwrap());
+	config.fallback_model = fallback_model.unwrap();
+	config.refuse_invalid_api_key = parse_flag(&refuse_invalid_api_key.unwrap());
+	config.max_tracked_channels = max_tracked_channels.unwrap();
+	config.state_flush_secs = state_flush_interval.unwrap();
   
 	// Initialize the logger
   let _ = try_init_custom_env_and_builder(
@@ -93,18 +517,71 @@ let matches = Command::new("RustGPT-Discord Bot")
     module_path!(),
     sensible_env_logger::pretty::formatted_timed_builder,
   );
+	if config.dry_run {
+		warn!("DRY RUN: prompts are echoed back instead of being sent to the API, do not use this in production");
+	}
 
-	// todo: add ability to load from file or database
-  let intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+	// todo: add ability to load from a database
+  let intents = GatewayIntents::GUILD_MESSAGES
+    | GatewayIntents::DIRECT_MESSAGES
+    | GatewayIntents::MESSAGE_CONTENT
+    | GatewayIntents::GUILD_MESSAGE_REACTIONS
+    | GatewayIntents::DIRECT_MESSAGE_REACTIONS;
 	let handler: HandlerStruct = HandlerStruct::new(Arc::new(config.clone()));
+	handler.load_state();
+	if let Some(addr) = config.metrics_addr.clone() {
+		tokio::spawn(metrics::serve(addr, handler.get_metrics()));
+	}
+	// save the changed state regularly, so a crash loses at most one interval
+	if config.state_flush_secs > 0 {
+		let handler = handler.clone();
+		let mut interval = tokio::time::interval(std::time::Duration::from_secs(config.state_flush_secs));
+		tokio::spawn(async move {
+			loop {
+				interval.tick().await;
+				match handler.save_state_if_dirty() {
+					Ok(Some(count)) => debug!("Flushed the state of {} users", count),
+					Ok(None) => {}
+					Err(why) => error!("Error flushing state: {:?}", why),
+				}
+			}
+		});
+	}
 	
   let mut client = serenity::Client::builder(&config.discord_token, intents)
     .intents(intents)
-    .event_handler(handler)
+    .event_handler(handler.clone())
     .await
     .expect("Error creating client");
 
-  if let Err(why) = client.start().await {
-    error!("Client error: {:?}", why);
-  }
+	// the handler asks to stop when the bot can't run, e.g. with a rejected API key
+	let shutdown = handler.shutdown_token();
+
+	// save the state and stop the shards on Ctrl-C instead of dropping everything
+	let shard_manager = client.shard_manager.clone();
+	tokio::spawn(async move {
+		if let Err(why) = tokio::signal::ctrl_c().await {
+			error!("Error listening for Ctrl-C: {:?}", why);
+			return;
+		}
+		info!("Shutting down");
+		match handler.save_state() {
+			Ok(count) => info!("Saved {} users", count),
+			Err(why) => error!("Error saving state: {:?}", why),
+		}
+		shard_manager.lock().await.shutdown_all().await;
+	});
+
+	let shard_manager = client.shard_manager.clone();
+	tokio::select! {
+		result = client.start() => {
+			if let Err(why) = result {
+				error!("Client error: {:?}", why);
+			}
+		}
+		_ = shutdown.cancelled() => {
+			shard_manager.lock().await.shutdown_all().await;
+			std::process::exit(1);
+		}
+	}
 }
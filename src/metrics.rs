@@ -0,0 +1,141 @@
+//! Prometheus metrics of the bot
+//!
+//! - `Metrics`: The counters and histograms of the bot, registered in one registry
+//! - `serve`: Serves the metrics and a health check over HTTP
+//!
+
+use std::sync::Arc;
+
+use prometheus::{
+	Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+};
+
+/// The metrics of the bot
+///
+/// ### Fields
+///
+/// * `chat_requests` - The number of chat requests made by users.
+/// * `openai_errors` - The number of failed requests to the OpenAI API.
+/// * `tokens_consumed` - The number of tokens used, labeled by model.
+/// * `openai_latency` - The time the OpenAI API took to answer, in seconds.
+///
+pub struct Metrics {
+	registry: Registry,
+	pub chat_requests: IntCounter,
+	pub openai_errors: IntCounter,
+	pub tokens_consumed: IntCounterVec,
+	pub openai_latency: Histogram,
+}
+impl Metrics {
+	pub fn new() -> Self {
+		let registry = Registry::new();
+		let chat_requests = IntCounter::new("chat_requests_total", "Number of chat requests made by users").unwrap();
+		let openai_errors = IntCounter::new("openai_errors_total", "Number of failed requests to the OpenAI API").unwrap();
+		let tokens_consumed = IntCounterVec::new(
+			Opts::new("tokens_consumed_total", "Number of tokens used"),
+			&["model"],
+		)
+		.unwrap();
+		let openai_latency = Histogram::with_opts(
+			HistogramOpts::new("openai_latency_seconds", "Time the OpenAI API took to answer")
+				.buckets(vec![0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0]),
+		)
+		.unwrap();
+
+		registry.register(Box::new(chat_requests.clone())).unwrap();
+		registry.register(Box::new(openai_errors.clone())).unwrap();
+		registry.register(Box::new(tokens_consumed.clone())).unwrap();
+		registry.register(Box::new(openai_latency.clone())).unwrap();
+
+		Self {
+			registry,
+			chat_requests,
+			openai_errors,
+			tokens_consumed,
+			openai_latency,
+		}
+	}
+
+	/// Renders the metrics in the Prometheus text format.
+	pub fn render(&self) -> String {
+		let mut buffer = Vec::new();
+		if let Err(why) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+			error!("Error encoding metrics: {:?}", why);
+		}
+		String::from_utf8(buffer).unwrap_or_default()
+	}
+}
+
+/// Serves `/metrics` and `/health` over HTTP until the process exits.
+///
+/// ### Arguments
+///
+/// * `addr` - The address to listen on, e.g. `0.0.0.0:9000`
+/// * `metrics` - The metrics to serve
+///
+pub async fn serve(addr: String, metrics: Arc<Metrics>) {
+	let listener = match TcpListener::bind(&addr).await {
+		Ok(listener) => listener,
+		Err(why) => {
+			error!("Error binding the metrics server to {}: {:?}", addr, why);
+			return;
+		}
+	};
+	info!("Serving metrics on http://{}/metrics", addr);
+	loop {
+		match listener.accept().await {
+			Ok((stream, _)) => {
+				let metrics = metrics.clone();
+				tokio::spawn(async move {
+					if let Err(why) = respond(stream, &metrics).await {
+						debug!("Error answering a metrics request: {:?}", why);
+					}
+				});
+			}
+			Err(why) => error!("Error accepting a metrics connection: {:?}", why),
+		}
+	}
+}
+
+/// Answers a single HTTP request with the metrics, the health check or a 404.
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+	let mut buffer = [0; 1024];
+	let read = stream.read(&mut buffer).await?;
+	let request = String::from_utf8_lossy(&buffer[..read]);
+	let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+	let (status, body) = match path {
+		"/metrics" => ("200 OK", metrics.render()),
+		"/health" => ("200 OK", "ok\n".to_string()),
+		_ => ("404 Not Found", "not found\n".to_string()),
+	};
+	let response = format!(
+		"HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status,
+		body.len(),
+		body
+	);
+	stream.write_all(response.as_bytes()).await?;
+	stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn render_includes_labeled_tokens() {
+		let metrics = Metrics::new();
+		metrics.chat_requests.inc();
+		metrics.tokens_consumed.with_label_values(&["gpt-3.5-turbo"]).inc_by(42);
+
+		let rendered = metrics.render();
+
+		assert!(rendered.contains("chat_requests_total 1"));
+		assert!(rendered.contains(r#"tokens_consumed_total{model="gpt-3.5-turbo"} 42"#));
+	}
+}
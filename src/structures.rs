@@ -1,5 +1,8 @@
+use rustc_hash::FxHashMap;
 use serde::{Deserialize, Serialize};
 
+use crate::users::Model;
+
 /// # ApitRequestBody
 /// 
 /// A struct holding the request body for the OpenAI API's completion endpoint.
@@ -12,7 +15,17 @@ use serde::{Deserialize, Serialize};
 /// * `messages` - A vector of `Message`s containing the prompt and completion candidates.
 /// * `max_tokens` - The maximum number of tokens to generate.
 /// * `temperature` - The temperature to use for the completion.
+/// * `top_p` - The probability mass of the tokens to sample from.
+/// * `n` - The number of completion choices to generate.
 /// * `user` - The user ID of the user making the request.
+/// * `tools` - The tools the model may call, if any.
+/// * `tool_choice` - Controls whether the model calls a tool, e.g. `auto` or `none`.
+/// * `response_format` - The format the model has to reply in, e.g. a JSON object.
+/// * `seed` - Makes the sampling deterministic, as far as the API supports it.
+/// * `presence_penalty` - Penalizes the tokens that are already in the text, between -2 and 2.
+/// * `frequency_penalty` - Penalizes the tokens by how often they are already in the text, between -2 and 2.
+/// 
+/// The penalties are only sent when they are set, 0 is the default of the API.
 /// 
 #[derive(Clone, Debug, Serialize)]
 pub struct ApiRequestBody {
@@ -20,7 +33,181 @@ pub struct ApiRequestBody {
 	pub messages: Vec<Message>,
 	pub max_tokens: u32,
 	pub temperature: f32,
+	pub top_p: f32,
+	pub n: u32,
 	pub user: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub tools: Option<Vec<ToolDefinition>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub tool_choice: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub response_format: Option<ResponseFormat>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seed: Option<u64>,
+	#[serde(skip_serializing_if = "is_zero")]
+	pub presence_penalty: f32,
+	#[serde(skip_serializing_if = "is_zero")]
+	pub frequency_penalty: f32,
+}
+
+fn is_zero(value: &f32) -> bool {
+	*value == 0.0
+}
+
+/// The format the model has to reply in, `json_object` makes it reply with a valid JSON object
+#[derive(Clone, Debug, Serialize)]
+pub struct ResponseFormat {
+	#[serde(rename = "type")]
+	pub format_type: String,
+}
+
+/// # ImageRequestBody
+///
+/// A struct holding the request body for the OpenAI API's image generation endpoint.
+///
+/// ### Fields
+///
+/// * `prompt` - The description of the image to generate.
+/// * `n` - The number of images to generate.
+/// * `size` - The size of the generated image. One of `256x256`, `512x512` or `1024x1024`.
+/// * `user` - The user ID of the user making the request.
+///
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageRequestBody {
+	pub prompt: String,
+	pub n: u32,
+	pub size: String,
+	pub user: String,
+}
+
+/// A struct holding the response from the OpenAI API's image generation endpoint.
+///
+/// For more information, see the [OpenAI API documentation](https://platform.openai.com/docs/api-reference/images/create).
+///
+/// ### Fields
+///
+/// * `created` - The time the images were created.
+/// * `data` - A vector of `ImageData` containing the generated images.
+///
+#[allow(dead_code)]
+#[derive(Clone, Debug, Deserialize)]
+pub struct ImageResponseStruct {
+	pub created: u64,
+	pub data: Vec<ImageData>,
+}
+
+/// A single generated image returned by the OpenAI API's image generation endpoint.
+///
+/// ### Fields
+///
+/// * `url` - The URL of the generated image.
+///
+#[derive(Clone, Debug, Deserialize)]
+pub struct ImageData {
+	pub url: String,
+}
+
+/// A struct holding the request body for the OpenAI API's embeddings endpoint.
+///
+/// ### Fields
+///
+/// * `model` - The model to use for the embeddings.
+/// * `input` - The texts to embed.
+///
+#[derive(Clone, Debug, Serialize)]
+pub struct EmbeddingRequestBody {
+	pub model: String,
+	pub input: Vec<String>,
+}
+
+/// A struct holding the response from the OpenAI API's embeddings endpoint.
+///
+/// For more information, see the [OpenAI API documentation](https://platform.openai.com/docs/api-reference/embeddings/create).
+///
+/// ### Fields
+///
+/// * `data` - An `EmbeddingData` per input.
+///
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmbeddingResponseStruct {
+	pub data: Vec<EmbeddingData>,
+}
+
+/// The embedding of a single input.
+///
+/// ### Fields
+///
+/// * `index` - The position of the input in the request.
+/// * `embedding` - The embedding vector.
+///
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmbeddingData {
+	pub index: usize,
+	pub embedding: Vec<f32>,
+}
+
+/// A struct holding the request body for the OpenAI API's moderation endpoint.
+///
+/// ### Fields
+///
+/// * `input` - The text to classify.
+///
+#[derive(Clone, Debug, Serialize)]
+pub struct ModerationRequestBody {
+	pub input: String,
+}
+
+/// A struct holding the response from the OpenAI API's moderation endpoint.
+///
+/// For more information, see the [OpenAI API documentation](https://platform.openai.com/docs/api-reference/moderations/create).
+///
+/// ### Fields
+///
+/// * `id` - The ID of the moderation request.
+/// * `model` - The model used to classify the input.
+/// * `results` - A vector of `ModerationResult`s, one per input.
+///
+#[allow(dead_code)]
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModerationResponseStruct {
+	pub id: String,
+	pub model: String,
+	pub results: Vec<ModerationResult>,
+}
+
+/// The moderation result for a single input.
+///
+/// ### Fields
+///
+/// * `flagged` - Whether the input violates OpenAI's usage policies.
+///
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModerationResult {
+	pub flagged: bool,
+}
+
+/// A struct holding the response from the OpenAI API's models endpoint.
+///
+/// For more information, see the [OpenAI API documentation](https://platform.openai.com/docs/api-reference/models/list).
+///
+/// ### Fields
+///
+/// * `data` - A vector of `ModelObject`s, one per model the API key can use.
+///
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelListResponse {
+	pub data: Vec<ModelObject>,
+}
+
+/// A model the API key can use.
+///
+/// ### Fields
+///
+/// * `id` - The name of the model, e.g. `gpt-3.5-turbo`.
+///
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelObject {
+	pub id: String,
 }
 
 /// A struct holding the response from the OpenAI API's completion endpoint.
@@ -35,6 +222,10 @@ pub struct ApiRequestBody {
 /// * `created` - The time the completion was created.
 /// * `choices` - A vector of `ChoiceStruct`s containing the completion candidates.
 /// * `usage` - A `UsageStruct` containing the usage statistics for the OpenAI API's completion endpoint.
+/// * `system_fingerprint` - The backend configuration that generated the completion. Replies with the
+/// same seed are only reproducible while it stays the same.
+/// * `cached` - Whether the response was taken from the response cache instead of the API.
+/// * `fallback_model` - The model that answered instead of the user's model, when it was out of capacity.
 /// 
 /// # Methods
 /// 
@@ -42,6 +233,7 @@ pub struct ApiRequestBody {
 /// * `usage` - Returns a `UsageStruct` containing the usage statistics for the OpenAI API's completion endpoint.
 /// 
 /// # ExampleS
+#[allow(dead_code)]
 #[derive(Clone, Debug, Deserialize)]
 pub struct ApiResponseStruct {
 	pub id: String,
@@ -49,6 +241,12 @@ pub struct ApiResponseStruct {
 	pub created: u64,
 	pub choices: Vec<ChoiceStruct>,
 	pub usage: UsageStruct,
+	#[serde(default)]
+	pub system_fingerprint: Option<String>,
+	#[serde(skip)]
+	pub cached: bool,
+	#[serde(skip)]
+	pub fallback_model: Option<Model>,
 }
 
 pub trait ApiResponse {
@@ -122,22 +320,25 @@ impl Usage for UsageStruct {
 /// 
 /// * `text` - Returns the text of the choice.
 /// * `index` - Returns the index of the choice.
-/// * `logprobs` - Returns the log probabilities for the choice.
+/// * `logprobs` - Returns the log probabilities for the choice, if they were returned.
 /// * `finish_reason` - Returns the reason the choice was finished.
 /// 
 /// ### Example
+#[allow(dead_code)]
 #[derive(Clone, Debug, Deserialize)]
 pub struct ChoiceStruct {
 	pub index: u32,
   pub message: Message,
+	#[serde(default)]
 	pub logprobs: Option<LogprobsStruct>,
 	pub finish_reason: String,
 }
 
+#[allow(dead_code)]
 pub trait Choice {
 	fn index(&self) -> u32;
 	fn message(&self) -> Message;
-	fn logprobs(&self) -> LogprobsStruct;
+	fn logprobs(&self) -> Option<LogprobsStruct>;
 	fn finish_reason(&self) -> String;
 }
 impl Choice for ChoiceStruct {
@@ -145,13 +346,10 @@ impl Choice for ChoiceStruct {
 		self.index
 	}
 	fn message(&self) -> Message {
-		Message {
-			role: self.message.role.clone(),
-			content: self.message.content.clone(),
-		}
+		self.message.clone()
 	}
-	fn logprobs(&self) -> LogprobsStruct {
-		self.logprobs.clone().unwrap()
+	fn logprobs(&self) -> Option<LogprobsStruct> {
+		self.logprobs.clone()
 	}
 	fn finish_reason(&self) -> String {
 		self.finish_reason.clone()
@@ -164,57 +362,449 @@ impl Choice for ChoiceStruct {
 /// 
 /// ### Fields
 /// 
-/// * `role` - The role of the message. Either the user or AI.
-/// * `content` - The content of the message.
+/// * `role` - The role of the message. Either the system, user, AI or a tool.
+/// * `content` - The content of the message, text or text with images. Empty when the AI only calls tools.
+/// * `name` - The name of the author, used to tell users apart in a shared conversation.
+/// * `tool_calls` - The tools the AI asks to call, if any.
+/// * `tool_call_id` - The ID of the tool call a `tool` message answers.
 /// 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Message {
 	pub role: String,
-	pub content: String,
+	#[serde(default, deserialize_with = "null_as_empty")]
+	pub content: MessageContent,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub tool_calls: Option<Vec<ToolCall>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub tool_call_id: Option<String>,
+}
+impl Message {
+	/// Creates a plain text message with the given role.
+	pub fn new(role: &str, content: String) -> Self {
+		Self {
+			role: role.to_string(),
+			content: content.into(),
+			..Default::default()
+		}
+	}
+}
+
+/// The API sends `null` content when a message only contains tool calls.
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<MessageContent, D::Error> {
+	Ok(Option::<MessageContent>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// The content of a `Message`, sent as a plain string unless it has images.
+///
+/// For more information, see the [OpenAI API documentation](https://platform.openai.com/docs/guides/vision).
+///
+/// ### Variants
+///
+/// * `Text` - Plain text, which is all the chat history and the replies of the AI ever hold.
+/// * `Parts` - Text and images, sent as an array of `ContentPart`s.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+	Text(String),
+	Parts(Vec<ContentPart>),
+}
+impl MessageContent {
+	/// Creates the content of a prompt with images, the text first.
+	pub fn with_images(text: String, image_urls: Vec<String>) -> Self {
+		let images = image_urls.into_iter().map(|url| ContentPart::ImageUrl {
+			image_url: ImageUrl { url },
+		});
+		Self::Parts(std::iter::once(ContentPart::Text { text }).chain(images).collect())
+	}
+	/// Returns the text of the content, the text parts joined by new lines when it has images.
+	pub fn as_text(&self) -> std::borrow::Cow<'_, str> {
+		match self {
+			Self::Text(text) => text.as_str().into(),
+			Self::Parts(parts) => parts
+				.iter()
+				.filter_map(|part| match part {
+					ContentPart::Text { text } => Some(text.as_str()),
+					ContentPart::ImageUrl { .. } => None,
+				})
+				.collect::<Vec<_>>()
+				.join("\n")
+				.into(),
+		}
+	}
+}
+impl Default for MessageContent {
+	fn default() -> Self {
+		Self::Text(String::new())
+	}
+}
+impl From<String> for MessageContent {
+	fn from(text: String) -> Self {
+		Self::Text(text)
+	}
+}
+impl std::fmt::Display for MessageContent {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.as_text())
+	}
+}
+impl PartialEq<&str> for MessageContent {
+	fn eq(&self, other: &&str) -> bool {
+		matches!(self, Self::Text(text) if text == other)
+	}
+}
+impl PartialEq<String> for MessageContent {
+	fn eq(&self, other: &String) -> bool {
+		*self == other.as_str()
+	}
+}
+
+/// A part of a `MessageContent` with images.
+///
+/// ### Variants
+///
+/// * `Text` - A text part.
+/// * `ImageUrl` - An image the model looks at, by its URL.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+	Text { text: String },
+	ImageUrl { image_url: ImageUrl },
+}
+
+/// The URL of an image in a `ContentPart`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImageUrl {
+	pub url: String,
 }
 
-/// A struct containing the log probabilities for the OpenAI API's completion endpoint.
+/// A tool the model may call, sent with the request.
 /// 
-/// This struct is returned by the OpenAI API's completion endpoint.
-/// For more information, see the [OpenAI API documentation](https://beta.openai.com/docs/api-reference/completions/create).
+/// For more information, see the [OpenAI API documentation](https://platform.openai.com/docs/guides/function-calling).
 /// 
 /// ### Fields
 /// 
-/// * `token_logprobs` - The log probabilities for each token.
-/// * `top_logprobs` - The log probabilities for each token.
-/// * `text_offset` - The offset of each token in the text.
+/// * `kind` - The type of the tool, always `function`.
+/// * `function` - The name, description and JSON schema of the parameters of the function.
 /// 
-/// ### Methods
+#[derive(Clone, Debug, Serialize)]
+pub struct ToolDefinition {
+	#[serde(rename = "type")]
+	pub kind: String,
+	pub function: FunctionDefinition,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FunctionDefinition {
+	pub name: String,
+	pub description: String,
+	pub parameters: serde_json::Value,
+}
+
+/// A call of a tool requested by the model.
 /// 
-/// * `token_logprobs` - Returns the log probabilities for each token.
-/// * `top_logprobs` - Returns the log probabilities for each token.
-/// * `text_offset` - Returns the offset of each token in the text.
+/// ### Fields
 /// 
-/// ### Example
-#[derive(Clone, Debug, Deserialize)]
+/// * `id` - The ID of the call, sent back with the result.
+/// * `kind` - The type of the tool, always `function`.
+/// * `function` - The name of the function and its arguments as a JSON string.
+/// 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ToolCall {
+	pub id: String,
+	#[serde(rename = "type")]
+	pub kind: String,
+	pub function: FunctionCall,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FunctionCall {
+	pub name: String,
+	pub arguments: String,
+}
+
+/// A struct containing the log probabilities for the OpenAI API's chat completion endpoint.
+///
+/// Only returned when `logprobs` is enabled in the request.
+/// For more information, see the [OpenAI API documentation](https://platform.openai.com/docs/api-reference/chat/object).
+///
+/// ### Fields
+///
+/// * `content` - The log probability of each token in the message content.
+///
+/// ### Methods
+///
+/// * `content` - Returns the log probability of each token, or nothing if they were not returned.
+///
+#[allow(dead_code)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct LogprobsStruct {
-	pub token_logprobs: Option<Vec<Vec<f32>>>,
-	pub top_logprobs: Option<Vec<Vec<f32>>>,
-	pub text_offset: Option<Vec<Vec<u32>>>,
+	#[serde(default)]
+	pub content: Option<Vec<TokenLogprob>>,
+}
+
+/// The log probability of a single token in the message content.
+///
+/// ### Fields
+///
+/// * `token` - The token.
+/// * `logprob` - The log probability of the token.
+/// * `bytes` - The UTF-8 bytes of the token, if it has a byte representation.
+/// * `top_logprobs` - The most likely tokens at this position and their log probabilities.
+///
+#[allow(dead_code)]
+#[derive(Clone, Debug, Deserialize)]
+pub struct TokenLogprob {
+	pub token: String,
+	pub logprob: f32,
+	#[serde(default)]
+	pub bytes: Option<Vec<u8>>,
+	#[serde(default)]
+	pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// One of the most likely tokens at a position of the message content.
+///
+/// ### Fields
+///
+/// * `token` - The token.
+/// * `logprob` - The log probability of the token.
+/// * `bytes` - The UTF-8 bytes of the token, if it has a byte representation.
+///
+#[allow(dead_code)]
+#[derive(Clone, Debug, Deserialize)]
+pub struct TopLogprob {
+	pub token: String,
+	pub logprob: f32,
+	#[serde(default)]
+	pub bytes: Option<Vec<u8>>,
 }
 
+#[allow(dead_code)]
 pub trait Logprobs {
-	fn token_logprobs(&self) -> Vec<Vec<f32>>;
-	fn top_logprobs(&self) -> Vec<Vec<f32>>;
-	fn text_offset(&self) -> Vec<Vec<u32>>;
+	fn content(&self) -> Vec<TokenLogprob>;
 }
 impl Logprobs for LogprobsStruct {
-	fn token_logprobs(&self) -> Vec<Vec<f32>> {
-		self.token_logprobs.clone().unwrap()
+	fn content(&self) -> Vec<TokenLogprob> {
+		self.content.clone().unwrap_or_default()
+	}
+}
+
+/// A struct holding an error response from the OpenAI API.
+///
+/// For more information, see the [OpenAI API documentation](https://platform.openai.com/docs/guides/error-codes).
+///
+/// ### Fields
+///
+/// * `error` - The details of the error.
+///
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApiErrorResponse {
+	pub error: ApiErrorDetail,
+}
+
+/// The details of an error returned by the OpenAI API.
+///
+/// ### Fields
+///
+/// * `message` - A human readable description of the error.
+/// * `kind` - The type of the error, e.g. `invalid_request_error`.
+/// * `code` - A machine readable error code, e.g. `invalid_api_key`.
+/// * `status` - The HTTP status code the API returned the error with.
+///
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApiErrorDetail {
+	pub message: String,
+	#[serde(rename = "type", default)]
+	pub kind: Option<String>,
+	#[serde(default)]
+	pub code: Option<String>,
+	#[serde(skip)]
+	pub status: Option<u16>,
+}
+
+/// An error raised while talking to an external API.
+///
+/// ### Variants
+///
+/// * `Request` - The request could not be sent or its response could not be parsed.
+/// * `Api` - The API answered with a non-success status code and the error it returned.
+/// * `Timeout` - The API did not answer within the request timeout.
+/// * `Cancelled` - The user stopped the generation with `/stop`.
+///
+#[derive(Debug)]
+pub enum BotError {
+	Request(reqwest::Error),
+	Api(ApiErrorDetail),
+	Timeout,
+	Cancelled,
+}
+impl BotError {
+	/// Returns a message describing the error that can be shown to the user.
+	pub fn user_message(&self) -> String {
+		match self {
+			BotError::Request(_) => "There was an error contacting the AI, please try again later.".to_string(),
+			BotError::Api(_) => self.to_string(),
+			BotError::Timeout => "The AI took too long to answer, please try again.".to_string(),
+			BotError::Cancelled => "Cancelled.".to_string(),
+		}
 	}
-	fn top_logprobs(&self) -> Vec<Vec<f32>> {
-		self.top_logprobs.clone().unwrap()
+	/// Returns whether the model is rate limited or overloaded, so another model may still answer.
+	///
+	/// A 429 for an exhausted quota is not, the quota is shared by every model of the account.
+	pub fn is_capacity_error(&self) -> bool {
+		match self {
+			BotError::Api(detail) => match detail.status {
+				Some(429) => detail.code.as_deref() != Some("insufficient_quota"),
+				Some(503) => true,
+				_ => false,
+			},
+			_ => false,
+		}
 	}
-	fn text_offset(&self) -> Vec<Vec<u32>> {
-		self.text_offset.clone().unwrap()
+}
+impl std::fmt::Display for BotError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			BotError::Request(why) => write!(f, "request error: {}", why),
+			BotError::Api(detail) => write!(f, "OpenAI says: {}", detail.message),
+			BotError::Timeout => write!(f, "request timed out"),
+			BotError::Cancelled => write!(f, "request cancelled"),
+		}
+	}
+}
+impl std::error::Error for BotError {}
+impl From<reqwest::Error> for BotError {
+	fn from(why: reqwest::Error) -> Self {
+		if why.is_timeout() {
+			BotError::Timeout
+		} else {
+			BotError::Request(why)
+		}
 	}
 }
 
+/// An error raised while resolving the bot configuration.
+///
+/// ### Variants
+///
+/// * `Missing` - The named variable was not found in the command-line arguments, environment variables, or the dotenv file.
+/// * `Invalid` - The named variable was found but its value could not be parsed.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+	Missing(String),
+	Invalid(String, String),
+}
+impl std::fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ConfigError::Missing(var_name) => write!(f, "missing {}", var_name),
+			ConfigError::Invalid(var_name, value) => {
+				write!(f, "invalid value {:?} for {}", value, var_name)
+			}
+		}
+	}
+}
+impl std::error::Error for ConfigError {}
+
+/// An error raised while acknowledging an interaction.
+///
+/// ### Variants
+///
+/// * `TimedOut` - Discord did not answer before the acknowledgement timeout.
+/// * `Discord` - Discord rejected the acknowledgement.
+///
+#[derive(Debug)]
+pub enum AckError {
+	TimedOut,
+	Discord(serenity::Error),
+}
+impl std::fmt::Display for AckError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			AckError::TimedOut => write!(f, "timed out while acknowledging the interaction"),
+			AckError::Discord(why) => write!(f, "error acknowledging the interaction: {}", why),
+		}
+	}
+}
+impl std::error::Error for AckError {}
+
+/// Whether the replies of a command are only shown to the user who used it.
+///
+/// ### Variants
+///
+/// * `AlwaysEphemeral` - Only the user sees the replies, whatever their chat privacy.
+/// * `NeverEphemeral` - Everyone in the channel sees the replies, whatever their chat privacy.
+/// * `FollowUserPrivacy` - Only the user sees the replies when their chat privacy is on.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum EphemeralPolicy {
+	AlwaysEphemeral,
+	NeverEphemeral,
+	FollowUserPrivacy,
+}
+impl EphemeralPolicy {
+	/// Returns whether the replies are ephemeral for a user with the given chat privacy.
+	pub fn is_ephemeral(&self, chat_privacy: bool) -> bool {
+		match self {
+			EphemeralPolicy::AlwaysEphemeral => true,
+			EphemeralPolicy::NeverEphemeral => false,
+			EphemeralPolicy::FollowUserPrivacy => chat_privacy,
+		}
+	}
+}
+
+/// The channels the bot chats in.
+///
+/// A denied channel is always refused. When channels are allowed, the bot only chats in those,
+/// otherwise it chats in every channel that is not denied.
+///
+/// ### Fields
+///
+/// * `allowed` - The IDs of the channels the bot is confined to, empty for every channel.
+/// * `denied` - The IDs of the channels the bot never chats in.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct ChannelFilter {
+	pub allowed: Vec<u64>,
+	pub denied: Vec<u64>,
+}
+impl ChannelFilter {
+	/// Returns whether the bot chats in the channel.
+	pub fn allows(&self, channel_id: u64) -> bool {
+		if self.denied.contains(&channel_id) {
+			return false;
+		}
+		self.allowed.is_empty() || self.allowed.contains(&channel_id)
+	}
+	/// Adds the channel to the allowed channels, and removes it from the denied ones.
+	pub fn allow(&mut self, channel_id: u64) {
+		self.denied.retain(|id| *id != channel_id);
+		if !self.allowed.contains(&channel_id) {
+			self.allowed.push(channel_id);
+		}
+	}
+	/// Adds the channel to the denied channels, and removes it from the allowed ones.
+	pub fn deny(&mut self, channel_id: u64) {
+		self.allowed.retain(|id| *id != channel_id);
+		if !self.denied.contains(&channel_id) {
+			self.denied.push(channel_id);
+		}
+	}
+	/// Removes the channel from both lists.
+	pub fn remove(&mut self, channel_id: u64) {
+		self.allowed.retain(|id| *id != channel_id);
+		self.denied.retain(|id| *id != channel_id);
+	}
+}
+
+/// The base URL of the OpenAI API, used unless `OPENAI_API_BASE_URL` is set
+pub const DEFAULT_API_BASE_URL: &str = "https://api.openai.com/v1";
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct ConfigStruct {
 	pub api_key: String,
@@ -222,7 +812,40 @@ pub struct ConfigStruct {
 	pub app_id: String,
 	pub rust_log: String,
 	pub global_log: String,
+	pub moderation: bool,
+	pub api_base_url: String,
+	pub api_auth_scheme: String,
+	pub rate_limit_requests: u32,
+	pub rate_limit_window: u64,
+	pub state_file: String,
+	pub ack_timeout_ms: u64,
+	pub dev_guild_id: Option<u64>,
+	pub command_cooldowns: FxHashMap<String, u64>,
+	pub command_ephemeral: FxHashMap<String, EphemeralPolicy>,
+	pub metrics_addr: Option<String>,
+	pub activity: String,
+	pub activity_rotation_secs: u64,
+	pub shared_channel_history: bool,
+	pub request_timeout_secs: u64,
+	pub channel_filter: ChannelFilter,
+	pub max_prompt_chars: usize,
+	pub semantic_memory: bool,
+	pub global_system_prefix: Option<String>,
+	pub openai_org: Option<String>,
+	pub max_concurrent_requests: usize,
+	pub dry_run: bool,
+	pub empty_reply_message: String,
+	pub use_webhook: bool,
+	pub response_cache_ttl_secs: u64,
+	pub response_cache_size: usize,
+	pub log_prompts: bool,
+	pub fallback_model: Option<Model>,
+	pub refuse_invalid_api_key: bool,
+	pub max_tracked_channels: usize,
+	pub state_flush_secs: u64,
+	pub reaction_controls: bool,
 }
+#[allow(dead_code)]
 pub trait Config {
 	fn new(api_key: String, discord_token: String, app_id: String, rust_log: String, global_log: String) -> Self;
 	fn api_key(&self) -> String;
@@ -230,6 +853,8 @@ pub trait Config {
 	fn app_id(&self) -> String;
 	fn rust_log(&self) -> String;
 	fn global_log(&self) -> String;
+	fn moderation(&self) -> bool;
+	fn api_url(&self, path: &str) -> String;
 }
 impl Config for ConfigStruct {
 		fn new(api_key: String, discord_token: String, app_id: String, rust_log: String, global_log: String) -> Self {
@@ -238,7 +863,39 @@ impl Config for ConfigStruct {
 				discord_token,
 				app_id,
 				rust_log,
-				global_log
+				global_log,
+				moderation: false,
+				api_base_url: DEFAULT_API_BASE_URL.to_string(),
+				api_auth_scheme: "bearer".to_string(),
+				rate_limit_requests: 10,
+				rate_limit_window: 60,
+				state_file: "state.json".to_string(),
+				ack_timeout_ms: 2000,
+				dev_guild_id: None,
+				command_cooldowns: FxHashMap::default(),
+				command_ephemeral: FxHashMap::default(),
+				metrics_addr: None,
+				activity: "/chat".to_string(),
+				activity_rotation_secs: 300,
+				shared_channel_history: false,
+				request_timeout_secs: 60,
+				channel_filter: ChannelFilter::default(),
+				max_prompt_chars: 4000,
+				semantic_memory: false,
+				global_system_prefix: None,
+				openai_org: None,
+				max_concurrent_requests: 8,
+				dry_run: false,
+				empty_reply_message: "I don't have a response for that.".to_string(),
+				use_webhook: false,
+				response_cache_ttl_secs: 0,
+				response_cache_size: 100,
+				log_prompts: false,
+				fallback_model: None,
+				refuse_invalid_api_key: false,
+				max_tracked_channels: 50,
+				state_flush_secs: 60,
+				reaction_controls: false,
 			}
 	}
 	fn api_key(&self) -> String {
@@ -256,4 +913,10 @@ impl Config for ConfigStruct {
 	fn global_log(&self) -> String {
 		self.global_log.clone()
 	}
+	fn moderation(&self) -> bool {
+		self.moderation
+	}
+	fn api_url(&self, path: &str) -> String {
+		format!("{}/{}", self.api_base_url.trim_end_matches('/'), path)
+	}
 }
@@ -0,0 +1,128 @@
+//! Tools the AI can call while generating a response
+//!
+//! - `ToolRegistry`: Holds the tools sent with chat requests and runs the ones the AI calls
+//! - `get_current_time`: The built-in tool returning the current UTC time
+//!
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use rustc_hash::FxHashMap;
+use serde_json::{json, Value};
+
+use crate::structures::{FunctionDefinition, ToolCall, ToolDefinition};
+
+/// A function run when the AI calls a tool, it receives the parsed arguments and returns the result
+pub type ToolFn = Arc<dyn Fn(&Value) -> String + Send + Sync>;
+
+/// The tools the AI can call
+///
+/// `ToolRegistry::default()` contains the built-in tools, more can be added with `register`.
+///
+/// ### Methods
+///
+/// * `register` - Adds a tool with its description, JSON schema of the parameters and function
+/// * `definitions` - Returns the definitions of all tools to send with a request
+/// * `call` - Runs the tool requested by a tool call and returns its result
+///
+#[derive(Clone)]
+pub struct ToolRegistry {
+	tools: FxHashMap<String, (ToolDefinition, ToolFn)>,
+}
+impl ToolRegistry {
+	pub fn new() -> Self {
+		Self {
+			tools: FxHashMap::default(),
+		}
+	}
+
+	pub fn register<F>(&mut self, name: &str, description: &str, parameters: Value, function: F)
+	where
+		F: Fn(&Value) -> String + Send + Sync + 'static,
+	{
+		let definition = ToolDefinition {
+			kind: "function".to_string(),
+			function: FunctionDefinition {
+				name: name.to_string(),
+				description: description.to_string(),
+				parameters,
+			},
+		};
+		self.tools.insert(name.to_string(), (definition, Arc::new(function)));
+	}
+
+	pub fn definitions(&self) -> Vec<ToolDefinition> {
+		let mut definitions: Vec<ToolDefinition> = self.tools.values().map(|(definition, _)| definition.clone()).collect();
+		definitions.sort_by(|a, b| a.function.name.cmp(&b.function.name));
+		definitions
+	}
+
+	pub fn call(&self, tool_call: &ToolCall) -> String {
+		let Some((_, function)) = self.tools.get(&tool_call.function.name) else {
+			return format!("Unknown tool: {}", tool_call.function.name);
+		};
+		// the model may send no or malformed arguments, tools get `null` in that case
+		let arguments = serde_json::from_str(&tool_call.function.arguments).unwrap_or(Value::Null);
+		function(&arguments)
+	}
+}
+impl Default for ToolRegistry {
+	fn default() -> Self {
+		let mut registry = Self::new();
+		registry.register(
+			"get_current_time",
+			"Returns the current date and time in UTC",
+			json!({ "type": "object", "properties": {} }),
+			|_| get_current_time(),
+		);
+		registry
+	}
+}
+
+/// Returns the current UTC time in RFC 3339 format
+fn get_current_time() -> String {
+	Utc::now().to_rfc3339()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::structures::{FunctionCall, Message};
+
+	fn tool_call(name: &str, arguments: &str) -> ToolCall {
+		ToolCall {
+			id: "call_1".to_string(),
+			kind: "function".to_string(),
+			function: FunctionCall {
+				name: name.to_string(),
+				arguments: arguments.to_string(),
+			},
+		}
+	}
+
+	#[test]
+	fn registered_tool_receives_arguments() {
+		let mut registry = ToolRegistry::new();
+		registry.register("echo", "Echoes the text", json!({}), |arguments| arguments["text"].to_string());
+
+		assert_eq!(registry.call(&tool_call("echo", r#"{"text":"hi"}"#)), r#""hi""#);
+	}
+
+	#[test]
+	fn unknown_tool_is_reported_to_the_model() {
+		let registry = ToolRegistry::default();
+
+		assert_eq!(registry.call(&tool_call("missing", "{}")), "Unknown tool: missing");
+	}
+
+	#[test]
+	fn message_with_only_tool_calls_deserializes() {
+		let message: Message = serde_json::from_str(
+			r#"{"role":"assistant","content":null,"tool_calls":[{"id":"call_1","type":"function","function":{"name":"get_current_time","arguments":"{}"}}]}"#,
+		)
+		.unwrap();
+
+		assert_eq!(message.content, "");
+		assert_eq!(message.tool_calls.unwrap()[0].function.name, "get_current_time");
+	}
+}
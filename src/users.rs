@@ -1,7 +1,60 @@
 use rustc_hash::FxHashMap;
 use serde::{Deserialize, Serialize};
-use serenity::model::prelude::{UserId, ChannelId};
-use chrono::{DateTime, Utc};
+use serde_json::json;
+use serenity::model::prelude::{UserId, ChannelId, MessageId};
+use chrono::{DateTime, Months, Utc};
+
+use crate::structures::UsageStruct;
+
+/// The amount of images a user can generate with `/imagine` by default
+pub const DEFAULT_IMAGE_QUOTA: u32 = 10;
+/// The most replies a user can ask to pick from
+pub const MAX_CHOICES: u32 = 5;
+/// The amount of exchanges kept in a channel's chat history by default
+pub const DEFAULT_MAX_HISTORY_ENTRIES: usize = 20;
+/// The settings that can be changed with `/settings set`
+pub const SETTINGS_KEYS: [&str; 12] = [
+	"privacy",
+	"personality",
+	"choices",
+	"history",
+	"temperature",
+	"top_p",
+	"presence_penalty",
+	"frequency_penalty",
+	"show_tokens",
+	"language",
+	"seed",
+	"template",
+];
+/// Where the user's message goes in a prompt template
+pub const PROMPT_TEMPLATE_PLACEHOLDER: &str = "{msg}";
+/// The session the chat history of a channel starts in, and the one stored data from before sessions is moved to
+pub const DEFAULT_SESSION: &str = "default";
+/// The most sessions kept per channel, the active one included
+pub const MAX_SESSIONS: usize = 10;
+/// The most characters of a session name
+pub const MAX_SESSION_NAME_CHARS: usize = 32;
+
+fn default_session() -> String {
+	DEFAULT_SESSION.to_string()
+}
+
+fn default_choices() -> u32 {
+	1
+}
+
+fn default_max_history_entries() -> usize {
+	DEFAULT_MAX_HISTORY_ENTRIES
+}
+
+fn default_temperature() -> f32 {
+	0.5
+}
+
+fn default_top_p() -> f32 {
+	1.0
+}
 
 /// # User
 /// the user struct contains information about a single user
@@ -22,8 +75,9 @@ use chrono::{DateTime, Utc};
 /// * `modify_usage` - modifies the user usage
 /// * `with_usage` - returns a reference to the user usage
 /// ---
+/// * `to_summary_json` - returns the stored user as JSON, with the chat histories reduced to counts
 /// 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct User {
 	pub id: UserId,
 	pub settings: UserSettings,
@@ -64,6 +118,17 @@ impl User {
 		{
 				with_usage(&self.usage)
 		}
+	pub fn to_summary_json(&self) -> serde_json::Value {
+		let mut value = serde_json::to_value(self).unwrap_or_default();
+		// the chat histories don't fit in a Discord message, so only their size is kept
+		if let Some(channels) = value["usage"]["channel_history"].as_object_mut() {
+			for channel in channels.values_mut() {
+				let entries = channel["chat_history"].as_array().map_or(0, Vec::len);
+				channel["chat_history"] = json!({ "entries": entries });
+			}
+		}
+		value
+	}
 }
 
 
@@ -76,6 +141,21 @@ impl User {
 /// * `personality` - the personality setting
 /// * `model` - the model setting
 /// * `command_state` - the command state setting (used for the command system)
+/// * `custom_personalities` - the private personalities created by the user
+/// * `image_quota` - the amount of images the user is allowed to generate
+/// * `token_budget` - the amount of tokens the user can use per month (0 is unlimited)
+/// * `choices` - the amount of replies to generate for the user to pick from
+/// * `max_history_entries` - the amount of exchanges kept per channel (0 is unlimited)
+/// * `temperature` - the sampling temperature, between 0 and 2
+/// * `top_p` - the nucleus sampling probability mass, between 0 and 1
+/// * `presence_penalty` - the penalty of the tokens already in the text, between -2 and 2
+/// * `frequency_penalty` - the penalty of the tokens by how often they are in the text, between -2 and 2
+/// * `show_token_footer` - whether the token usage is appended to each reply
+/// * `json_mode` - whether the AI has to reply with a JSON object
+/// * `locale` - the language the AI replies in, detected from Discord unless set with `/config`
+/// * `seed` - the seed sent with each request for reproducible replies, if any
+/// * `prompt_template` - the text every prompt is wrapped in, with `{msg}` where the prompt goes, if any
+/// * `response_length` - the length of the replies asked for with `/concise` or `/verbose`, on top of the personality
 /// 
 /// 
 /// ### Methods
@@ -88,15 +168,78 @@ impl User {
 /// * `set_personality` - sets the personality setting
 /// ---
 /// * `get_model` - returns a reference to the model setting
+/// * `get_image_quota` - returns the image quota setting
+/// * `get_token_budget` - returns the monthly token budget setting
+/// * `set_token_budget` - sets the monthly token budget setting
+/// * `get_choices` - returns the amount of replies to generate
+/// * `set_choices` - sets the amount of replies to generate, between 1 and `MAX_CHOICES`
+/// * `get_max_history_entries` - returns the amount of exchanges kept per channel
+/// * `set_max_history_entries` - sets the amount of exchanges kept per channel
+/// * `get_temperature` - returns the sampling temperature
+/// * `set_temperature` - sets the sampling temperature, if it is between 0 and 2
+/// * `get_top_p` - returns the nucleus sampling probability mass
+/// * `set_top_p` - sets the nucleus sampling probability mass, if it is between 0 and 1
+/// * `get_presence_penalty` - returns the penalty of the tokens already in the text
+/// * `set_presence_penalty` - sets the penalty of the tokens already in the text, if it is between -2 and 2
+/// * `get_frequency_penalty` - returns the penalty of the tokens by how often they are in the text
+/// * `set_frequency_penalty` - sets the penalty of the tokens by how often they are in the text, if it is between -2 and 2
+/// * `get_show_token_footer` - returns whether the token usage is appended to each reply
+/// * `set_show_token_footer` - sets whether the token usage is appended to each reply
+/// * `get_json_mode` - returns whether the AI has to reply with a JSON object
+/// * `set_json_mode` - sets whether the AI has to reply with a JSON object, if the model supports it
+/// * `get_locale` - returns the language the AI replies in
+/// * `set_locale` - sets the language the AI replies in
+/// * `get_seed` - returns the seed sent with each request
+/// * `set_seed` - sets or removes the seed sent with each request
+/// * `get_prompt_template` - returns the text every prompt is wrapped in
+/// * `set_prompt_template` - sets or removes the prompt template, if it has `{msg}` exactly once
+/// * `apply_prompt_template` - wraps a prompt in the prompt template, if any
+/// * `get_response_length` - returns the length of the replies asked for, if any
+/// * `set_response_length` - sets or removes the length of the replies asked for
 /// * `set_command_state` - sets the command state setting
+/// * `set_by_key` - sets one of the `SETTINGS_KEYS` from its text value
+/// ---
+/// * `add_custom_personality` - adds or replaces a private personality
 /// 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct UserSettings {
 	pub chat_privacy: bool,
 	pub personality: Personality,
 	// the model represents which model is being used for the token usage and limit
 	pub model: Model,
 	pub command_state: CommandState,
+	pub custom_personalities: Vec<Personality>,
+	pub image_quota: u32,
+	pub token_budget: u32,
+	#[serde(default = "default_choices")]
+	pub choices: u32,
+	#[serde(default = "default_max_history_entries")]
+	pub max_history_entries: usize,
+	#[serde(default = "default_temperature")]
+	pub temperature: f32,
+	#[serde(default = "default_top_p")]
+	pub top_p: f32,
+	#[serde(default)]
+	pub presence_penalty: f32,
+	#[serde(default)]
+	pub frequency_penalty: f32,
+	#[serde(default)]
+	pub show_token_footer: bool,
+	#[serde(default)]
+	pub json_mode: bool,
+	#[serde(default)]
+	pub locale: Option<String>,
+	#[serde(default)]
+	pub seed: Option<u64>,
+	#[serde(default)]
+	pub prompt_template: Option<String>,
+	#[serde(default)]
+	pub response_length: Option<ResponseLength>,
+}
+impl Default for UserSettings {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 impl UserSettings {
 	pub fn new() -> Self {
@@ -105,6 +248,21 @@ impl UserSettings {
 			personality: Personality::default(),
 			model: Model::default(),
 			command_state: CommandState::None,
+			custom_personalities: Vec::new(),
+			image_quota: DEFAULT_IMAGE_QUOTA,
+			token_budget: 0,
+			choices: 1,
+			max_history_entries: DEFAULT_MAX_HISTORY_ENTRIES,
+			temperature: default_temperature(),
+			top_p: default_top_p(),
+			presence_penalty: 0.0,
+			frequency_penalty: 0.0,
+			show_token_footer: false,
+			json_mode: false,
+			locale: None,
+			seed: None,
+			prompt_template: None,
+			response_length: None,
 		}
 	}
 	pub fn get_chat_privacy(&self) -> bool {
@@ -122,9 +280,197 @@ impl UserSettings {
 	pub fn get_model(&self) -> &Model {
 		&self.model
 	}
+	pub fn get_image_quota(&self) -> u32 {
+		self.image_quota
+	}
+	pub fn get_token_budget(&self) -> u32 {
+		self.token_budget
+	}
+	pub fn set_token_budget(&mut self, token_budget: u32) {
+		self.token_budget = token_budget;
+	}
+	pub fn get_choices(&self) -> u32 {
+		self.choices
+	}
+	pub fn set_choices(&mut self, choices: u32) {
+		self.choices = choices.clamp(1, MAX_CHOICES);
+	}
+	pub fn get_max_history_entries(&self) -> usize {
+		self.max_history_entries
+	}
+	pub fn set_max_history_entries(&mut self, max_history_entries: usize) {
+		self.max_history_entries = max_history_entries;
+	}
+	pub fn get_temperature(&self) -> f32 {
+		self.temperature
+	}
+	pub fn set_temperature(&mut self, temperature: f32) -> Result<(), String> {
+		if !(0.0..=2.0).contains(&temperature) {
+			return Err(format!("The temperature must be between 0 and 2, not {}.", temperature));
+		}
+		self.temperature = temperature;
+		Ok(())
+	}
+	pub fn get_top_p(&self) -> f32 {
+		self.top_p
+	}
+	pub fn set_top_p(&mut self, top_p: f32) -> Result<(), String> {
+		if !(0.0..=1.0).contains(&top_p) {
+			return Err(format!("top_p must be between 0 and 1, not {}.", top_p));
+		}
+		self.top_p = top_p;
+		Ok(())
+	}
+	pub fn get_presence_penalty(&self) -> f32 {
+		self.presence_penalty
+	}
+	pub fn set_presence_penalty(&mut self, presence_penalty: f32) -> Result<(), String> {
+		if !(-2.0..=2.0).contains(&presence_penalty) {
+			return Err(format!("presence_penalty must be between -2 and 2, not {}.", presence_penalty));
+		}
+		self.presence_penalty = presence_penalty;
+		Ok(())
+	}
+	pub fn get_frequency_penalty(&self) -> f32 {
+		self.frequency_penalty
+	}
+	pub fn set_frequency_penalty(&mut self, frequency_penalty: f32) -> Result<(), String> {
+		if !(-2.0..=2.0).contains(&frequency_penalty) {
+			return Err(format!("frequency_penalty must be between -2 and 2, not {}.", frequency_penalty));
+		}
+		self.frequency_penalty = frequency_penalty;
+		Ok(())
+	}
+	pub fn get_show_token_footer(&self) -> bool {
+		self.show_token_footer
+	}
+	pub fn set_show_token_footer(&mut self, show_token_footer: bool) {
+		self.show_token_footer = show_token_footer;
+	}
+	pub fn get_json_mode(&self) -> bool {
+		self.json_mode
+	}
+	pub fn set_json_mode(&mut self, json_mode: bool) -> Result<(), String> {
+		if json_mode && !self.model.supports_json_mode() {
+			return Err(format!("{} can't be asked to reply with JSON.", self.model.get_name()));
+		}
+		self.json_mode = json_mode;
+		Ok(())
+	}
+	pub fn get_locale(&self) -> Option<&String> {
+		self.locale.as_ref()
+	}
+	pub fn set_locale(&mut self, locale: Option<String>) {
+		self.locale = locale;
+	}
+	pub fn get_seed(&self) -> Option<u64> {
+		self.seed
+	}
+	pub fn set_seed(&mut self, seed: Option<u64>) {
+		self.seed = seed;
+	}
+	pub fn get_prompt_template(&self) -> Option<&String> {
+		self.prompt_template.as_ref()
+	}
+	pub fn set_prompt_template(&mut self, prompt_template: Option<String>) -> Result<(), String> {
+		if let Some(prompt_template) = &prompt_template {
+			if prompt_template.matches(PROMPT_TEMPLATE_PLACEHOLDER).count() != 1 {
+				return Err(format!(
+					"The template has to contain {} exactly once, where your message goes.",
+					PROMPT_TEMPLATE_PLACEHOLDER
+				));
+			}
+		}
+		self.prompt_template = prompt_template;
+		Ok(())
+	}
+	pub fn get_response_length(&self) -> Option<ResponseLength> {
+		self.response_length
+	}
+	pub fn set_response_length(&mut self, response_length: Option<ResponseLength>) {
+		self.response_length = response_length;
+	}
+	pub fn apply_prompt_template(&self, prompt: &str) -> String {
+		match &self.prompt_template {
+			Some(prompt_template) => prompt_template.replacen(PROMPT_TEMPLATE_PLACEHOLDER, prompt, 1),
+			None => prompt.to_string(),
+		}
+	}
+	#[allow(dead_code)]
 	pub fn set_command_state(&mut self, command_state: CommandState) {
 		self.command_state = command_state;
 	}
+	/// Sets one of the `SETTINGS_KEYS` from its text value, as entered with `/settings set`.
+	///
+	/// The personality is looked up in `personas` and the user's private personalities.
+	pub fn set_by_key(&mut self, key: &str, value: &str, personas: &[Personality]) -> Result<(), String> {
+		let value = value.trim();
+		let invalid = || format!("{:?} is not a valid value for {}.", value, key);
+		let parse_bool = || match value.to_lowercase().as_str() {
+			"true" | "yes" | "on" | "1" => Ok(true),
+			"false" | "no" | "off" | "0" => Ok(false),
+			_ => Err(invalid()),
+		};
+		match key {
+			"privacy" => {
+				let private = match value.to_lowercase().as_str() {
+					"private" => true,
+					"public" => false,
+					_ => parse_bool()?,
+				};
+				self.set_chat_privacy(private);
+			}
+			"personality" => {
+				let personality = personas
+					.iter()
+					.chain(self.custom_personalities.iter())
+					.find(|personality| personality.name == value)
+					.cloned()
+					.ok_or_else(|| format!("There is no {:?} personality.", value))?;
+				self.set_personality(personality);
+			}
+			"choices" => self.set_choices(value.parse().map_err(|_| invalid())?),
+			"history" => self.set_max_history_entries(value.parse().map_err(|_| invalid())?),
+			"temperature" => self.set_temperature(value.parse().map_err(|_| invalid())?)?,
+			"top_p" => self.set_top_p(value.parse().map_err(|_| invalid())?)?,
+			"presence_penalty" => self.set_presence_penalty(value.parse().map_err(|_| invalid())?)?,
+			"frequency_penalty" => self.set_frequency_penalty(value.parse().map_err(|_| invalid())?)?,
+			"show_tokens" => self.set_show_token_footer(parse_bool()?),
+			// the language is detected from Discord again once it is cleared
+			"language" => self.set_locale(
+				Some(value)
+					.filter(|value| !value.is_empty() && !value.eq_ignore_ascii_case("auto"))
+					.map(str::to_string),
+			),
+			"seed" => match value.to_lowercase().as_str() {
+				"" | "clear" => self.set_seed(None),
+				_ => self.set_seed(Some(value.parse().map_err(|_| invalid())?)),
+			},
+			"template" => match value.to_lowercase().as_str() {
+				"" | "clear" => self.set_prompt_template(None)?,
+				_ => self.set_prompt_template(Some(value.to_string()))?,
+			},
+			_ => {
+				return Err(format!(
+					"Unknown setting {:?}, the settings are {}.",
+					key,
+					SETTINGS_KEYS.join(", ")
+				))
+			}
+		}
+		Ok(())
+	}
+	pub fn add_custom_personality(&mut self, personality: Personality) {
+		if let Some(existing) = self
+			.custom_personalities
+			.iter_mut()
+			.find(|p| p.name == personality.name)
+		{
+			*existing = personality;
+		} else {
+			self.custom_personalities.push(personality);
+		}
+	}
 }
 
 
@@ -135,21 +481,31 @@ impl UserSettings {
 /// ### Fields
 /// * `chat_count` - the amount of messages sent by the user in the given channel
 /// * `last_chat` - the time of the last message sent by the user in the given channel
-/// * `total_tokens` - the total amount of tokens used by the user
+/// * `total_tokens` - the lifetime amount of tokens billed to the user, never decreased when a chat history is trimmed or cleared
 /// * `chat_history` - the history of the messages sent by the user in the given channel
+/// * `images_generated` - the amount of images generated by the user
+/// * `tokens_this_period` - the amount of tokens used since `period_start`
+/// * `period_start` - the start of the current monthly budget period
+/// * `estimated_cost` - the estimated lifetime spend of the user in USD, see `estimate_cost`
 /// 
 /// 
 /// ### Methods
 /// * `new` - creates a new UserUsage struct
 /// ---
-/// * `modify_channel_data` - modifies the channel data of the given channel
-/// * `contains_channel` - checks if the user has data for the given channel
-/// * `add_channel` - adds a new channel to the user
-/// * `reset_channel_usage` - resets the usage of the given channel
-/// ---
-/// * `increase_chat_count` - increases the chat count by 1
+/// * `increase_chat_count` - increases the chat count by 1 and sets the time of the last chat to now
 /// * `get_total_tokens` - returns the total amount of tokens used by the user
-/// * `add_total_tokens` - adds the given amount of tokens to the total tokens
+/// * `add_total_tokens` - adds the given amount of tokens to the total tokens and the tokens of the period
+/// ---
+/// * `get_images_generated` - returns the amount of images generated by the user
+/// * `increase_images_generated` - increases the images generated by 1
+/// ---
+/// * `get_estimated_cost` - returns the estimated lifetime spend of the user in USD
+/// * `add_cost` - adds the estimated cost of a response, see `estimate_cost`
+/// ---
+/// * `trim_channels` - drops the least recently used channels until at most the given amount is left (0 is unlimited)
+/// ---
+/// * `roll_period` - starts a new budget period if a month has elapsed
+/// * `is_over_budget` - checks if the tokens used this period exceed the given budget
 /// 
 /// 
 /// ### Usage
@@ -159,13 +515,22 @@ impl UserSettings {
 /// let mut usage = UserUsage::new();
 /// ```
 /// 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct UserUsage {
 	pub chat_count: u32,
 	pub last_chat: DateTime<Utc>,
 	pub total_tokens: u32,
 	pub channel_history: FxHashMap<ChannelId, UserChannelData>,
-
+	pub images_generated: u32,
+	pub tokens_this_period: u32,
+	pub period_start: DateTime<Utc>,
+	#[serde(default)]
+	pub estimated_cost: f64,
+}
+impl Default for UserUsage {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 impl UserUsage {
 	pub fn new() -> Self {
@@ -174,25 +539,12 @@ impl UserUsage {
 			last_chat: Utc::now(),
 			total_tokens: 0,
 			channel_history: FxHashMap::default(),
+			images_generated: 0,
+			tokens_this_period: 0,
+			period_start: Utc::now(),
+			estimated_cost: 0.0,
 		}
 	}
-// Method to modify or add a UserChannelData based on ChannelId
-pub fn modify_channel_data<F>(&mut self, channel_id: ChannelId, modify: F)
-	where
-			F: FnOnce(&mut UserChannelData),
-	{
-		let channel_data = self
-			.channel_history
-			.entry(channel_id)
-			.or_insert(UserChannelData::default());
-		modify(channel_data);
-	}	
-	pub fn contains_channel(&self, channel: ChannelId) -> bool {
-		self.channel_history.contains_key(&channel)
-	}
-	pub fn add_channel(&mut self, channel: ChannelId) {
-		self.channel_history.insert(channel, UserChannelData::new(channel));
-	}
 	// pub fn with_channel_data<F, R>(&mut self, channel: ChannelId, f: F) -> Option<R>
 	// 	where
 	// 		F: FnOnce(&UserChannelData) -> R,
@@ -202,12 +554,6 @@ pub fn modify_channel_data<F>(&mut self, channel_id: ChannelId, modify: F)
 	// 		// If the channel history exists, execute the given function on it.
 	// 		.map(f)
 	// }	
-	pub fn reset_channel_usage(&mut self, channel: ChannelId) {
-		if let Some(channel_data) = self.channel_history.get_mut(&channel) {
-			channel_data.tokens_used = 0;
-			channel_data.chat_history.clear();
-		}
-	}
 	
 	pub fn get_total_tokens(&self) -> u32 {
 		self.total_tokens
@@ -215,9 +561,55 @@ pub fn modify_channel_data<F>(&mut self, channel_id: ChannelId, modify: F)
 
 	pub fn increase_chat_count(&mut self) {
 		self.chat_count += 1;
+		self.last_chat = Utc::now();
 	}
 	pub fn add_total_tokens(&mut self, tokens: u32) {
 		self.total_tokens += tokens;
+		self.tokens_this_period += tokens;
+	}
+	pub fn get_estimated_cost(&self) -> f64 {
+		self.estimated_cost
+	}
+	// the cost is added when the response is received, so switching models later doesn't reprice it
+	pub fn add_cost(&mut self, usage: &UsageStruct, model: &Model) {
+		self.estimated_cost += estimate_cost(usage, model);
+	}
+	pub fn get_images_generated(&self) -> u32 {
+		self.images_generated
+	}
+	pub fn increase_images_generated(&mut self) {
+		self.images_generated += 1;
+	}
+	pub fn trim_channels(&mut self, max_channels: usize) {
+		if max_channels == 0 {
+			return;
+		}
+		while self.channel_history.len() > max_channels {
+			let least_recently_used = self
+				.channel_history
+				.values()
+				.min_by_key(|channel_data| channel_data.last_used)
+				.map(|channel_data| channel_data.channel_id);
+			let Some(channel_id) = least_recently_used else {
+				break;
+			};
+			self.channel_history.remove(&channel_id);
+			debug!("dropped the chat data of the least recently used channel {}", channel_id);
+		}
+	}
+	pub fn roll_period(&mut self, now: DateTime<Utc>) {
+		let period_end = self
+			.period_start
+			.checked_add_months(Months::new(1))
+			.unwrap_or(now);
+		if now >= period_end {
+			self.period_start = now;
+			self.tokens_this_period = 0;
+		}
+	}
+	// a budget of 0 means the user has no limit
+	pub fn is_over_budget(&self, token_budget: u32) -> bool {
+		token_budget != 0 && self.tokens_this_period >= token_budget
 	}
 	
 }
@@ -235,6 +627,10 @@ pub fn modify_channel_data<F>(&mut self, channel_id: ChannelId, modify: F)
 /// * `tokens_amount` - the amount of tokens used by the message
 /// * `user_tokens` - the amount of tokens used by the user
 /// * `completion_tokens` - the amount of tokens used by the bot
+/// * `author` - the user who sent the message, used to tell users apart in a shared channel
+/// * `truncated` - whether the bot's message was cut off at the token limit, so it can be continued
+/// * `embedding` - the embedding of the combined message, set once it is needed by the semantic memory
+/// * `reply_message_ids` - the Discord messages of the bot's reply, one per part of a long reply, if it is public and can be edited in place
 /// 
 /// 
 /// ### Methods
@@ -244,7 +640,7 @@ pub fn modify_channel_data<F>(&mut self, channel_id: ChannelId, modify: F)
 /// * `get_total_tokens` - returns the total tokens used by the message
 /// 
 /// 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct UserChatHistoryEntry {
 	pub message: String, // the combined message from the user and the bot
 	pub user_message: String, // the message sent by the user
@@ -253,6 +649,14 @@ pub struct UserChatHistoryEntry {
 	pub total_tokens: u32,
 	pub user_tokens: u32,
 	pub completion_tokens: u32,
+	#[serde(default)]
+	pub author: Option<UserId>,
+	#[serde(default)]
+	pub truncated: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub embedding: Option<Vec<f32>>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub reply_message_ids: Vec<MessageId>,
 }
 
 impl UserChatHistoryEntry {
@@ -272,6 +676,10 @@ impl UserChatHistoryEntry {
 			total_tokens,
 			user_tokens,
 			completion_tokens,
+			author: None,
+			truncated: false,
+			embedding: None,
+			reply_message_ids: Vec::new(),
 		}
 	}
 
@@ -301,23 +709,50 @@ impl UserChatHistoryEntry {
 /// 
 /// ### Fields
 /// * `channel_id` - the id of the channel
-/// * `tokens_used` - the amount of tokens used in the channel
-/// * `chat_history` - the chat history of the channel
+/// * `tokens_used` - the tokens of the entries currently in the chat history, i.e. the context sent with a prompt
+/// * `chat_history` - the chat history of the active session of the channel
+/// * `system_prompt` - a custom system prompt for the channel that replaces the personality prompt
+/// * `last_used` - when the chat data was last changed, the least recently used channel is dropped first
+/// * `active_session` - the name of the session `chat_history` belongs to
+/// * `sessions` - the chat histories of the other sessions, by name
 /// 
 /// 
 /// ### Methods
 /// * `new` - creates a new UserChannelData struct
-/// * `default` - returns the default UserChannelData struct
 /// * `add_chat_history_entry` - adds a chat history entry to the chat history
-/// * `remove_oldest_entry` - removes the oldest entry from the chat history
-/// * `get_tokens_used` - returns the amount of tokens used in the channel
+/// * `remove_oldest_entry` - removes and returns the oldest entry from the chat history
+/// * `trim_to_token_limit` - removes the oldest entries until the tokens used are within the limit
+/// * `trim_to_entry_limit` - removes the oldest entries until at most the given amount is left (0 is unlimited)
+/// * `remove_latest_entry` - removes and returns the latest entry from the chat history
+/// * `continue_latest_entry` - appends a continuation to the bot's message of the latest entry
+/// * `remove_entries_by` - removes the entries sent by the given user
+/// * `remove_entry_at` - removes and returns the entry with the given timestamp
+/// * `clear_chat_history` - removes every entry and resets the tokens used
+/// * `clear_chat_history_of` - removes the entries of the active session sent by the given user
+/// * `get_tokens_used` - returns the tokens of the entries currently in the chat history
 /// * `add_tokens_used` - adds tokens to the tokens used
+/// * `recount_tokens_used` - recomputes the tokens used from the entries of the chat history
+/// * `set_system_prompt` - sets or clears the custom system prompt
+/// * `get_active_session` - returns the name of the active session
+/// * `session_names` - returns the names of every session, sorted
+/// * `new_session` - starts a new empty session and switches to it
+/// * `switch_session` - switches to another session
 /// 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// The active session is kept in `chat_history`, so the chat data stored before sessions existed
+/// is the default session.
+/// 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct UserChannelData {
 	pub channel_id: ChannelId,
 	pub tokens_used: u32,
 	pub chat_history: Vec<UserChatHistoryEntry>,
+	pub system_prompt: Option<String>,
+	#[serde(default = "Utc::now")]
+	pub last_used: DateTime<Utc>,
+	#[serde(default = "default_session")]
+	pub active_session: String,
+	#[serde(default)]
+	pub sessions: FxHashMap<String, Vec<UserChatHistoryEntry>>,
 }
 impl UserChannelData {
 	pub fn new(channel_id: ChannelId) -> Self {
@@ -325,13 +760,10 @@ impl UserChannelData {
 			channel_id,
 			tokens_used: 0,
 			chat_history: Vec::new(),
-		}
-	}
-	pub fn default() -> Self {
-		Self {
-			channel_id: ChannelId(0),
-			tokens_used: 0,
-			chat_history: Vec::new(),
+			system_prompt: None,
+			last_used: Utc::now(),
+			active_session: default_session(),
+			sessions: FxHashMap::default(),
 		}
 	}
 	pub fn add_chat_history_entry(&mut self, entry: UserChatHistoryEntry) {
@@ -340,9 +772,59 @@ impl UserChannelData {
 		self.chat_history.push(entry);
 		debug!("channel chat history length: {}", self.chat_history.len());
 	}
-	pub fn remove_oldest_entry(&mut self) {
-		self.tokens_used -= self.chat_history[0].total_tokens;
-		self.chat_history.remove(0);
+	pub fn remove_oldest_entry(&mut self) -> Option<UserChatHistoryEntry> {
+		if self.chat_history.is_empty() {
+			return None;
+		}
+		let entry = self.chat_history.remove(0);
+		self.tokens_used = self.tokens_used.saturating_sub(entry.total_tokens);
+		Some(entry)
+	}
+	pub fn trim_to_token_limit(&mut self, token_limit: u32) {
+		while self.tokens_used > token_limit && self.remove_oldest_entry().is_some() {}
+	}
+	pub fn trim_to_entry_limit(&mut self, max_entries: usize) {
+		if max_entries == 0 {
+			return;
+		}
+		while self.chat_history.len() > max_entries && self.remove_oldest_entry().is_some() {}
+	}
+	pub fn remove_latest_entry(&mut self) -> Option<UserChatHistoryEntry> {
+		let entry = self.chat_history.pop()?;
+		self.tokens_used = self.tokens_used.saturating_sub(entry.total_tokens);
+		Some(entry)
+	}
+	pub fn continue_latest_entry(&mut self, continuation: &str, completion_tokens: u32, total_tokens: u32, truncated: bool) {
+		let Some(entry) = self.chat_history.last_mut() else {
+			return;
+		};
+		entry.ai_message.push_str(continuation);
+		entry.message.push_str(continuation);
+		entry.completion_tokens += completion_tokens;
+		entry.total_tokens += total_tokens;
+		entry.truncated = truncated;
+		self.tokens_used += total_tokens;
+	}
+	pub fn remove_entry_at(&mut self, timestamp: DateTime<Utc>) -> Option<UserChatHistoryEntry> {
+		let index = self.chat_history.iter().position(|entry| entry.timestamp == timestamp)?;
+		let entry = self.chat_history.remove(index);
+		self.tokens_used = self.tokens_used.saturating_sub(entry.total_tokens);
+		Some(entry)
+	}
+	pub fn remove_entries_by(&mut self, author: UserId) {
+		self.chat_history.retain(|entry| entry.author != Some(author));
+		for chat_history in self.sessions.values_mut() {
+			chat_history.retain(|entry| entry.author != Some(author));
+		}
+		self.recount_tokens_used();
+	}
+	pub fn clear_chat_history(&mut self) {
+		self.tokens_used = 0;
+		self.chat_history.clear();
+	}
+	pub fn clear_chat_history_of(&mut self, author: UserId) {
+		self.chat_history.retain(|entry| entry.author != Some(author));
+		self.recount_tokens_used();
 	}
 	pub fn get_tokens_used(&self) -> &u32 {
 		&self.tokens_used
@@ -350,6 +832,50 @@ impl UserChannelData {
 	pub fn add_tokens_used(&mut self, tokens: u32) {
 		self.tokens_used += tokens;
 	}
+	pub fn recount_tokens_used(&mut self) {
+		self.tokens_used = self.chat_history.iter().map(|entry| entry.total_tokens).sum();
+	}
+	pub fn set_system_prompt(&mut self, system_prompt: Option<String>) {
+		self.system_prompt = system_prompt;
+	}
+	pub fn get_active_session(&self) -> &str {
+		&self.active_session
+	}
+	pub fn session_names(&self) -> Vec<String> {
+		let mut names: Vec<String> = self.sessions.keys().cloned().collect();
+		names.push(self.active_session.clone());
+		names.sort();
+		names
+	}
+	pub fn new_session(&mut self, name: &str) -> Result<(), String> {
+		let name = name.trim();
+		if name.is_empty() || name.chars().count() > MAX_SESSION_NAME_CHARS {
+			return Err(format!("The name of a session has 1 to {} characters.", MAX_SESSION_NAME_CHARS));
+		}
+		if name == self.active_session || self.sessions.contains_key(name) {
+			return Err(format!("There is already a session named {}.", name));
+		}
+		if self.sessions.len() + 1 >= MAX_SESSIONS {
+			return Err(format!("There are at most {} sessions per channel.", MAX_SESSIONS));
+		}
+		self.sessions.insert(name.to_string(), Vec::new());
+		self.switch_session(name)
+	}
+	pub fn switch_session(&mut self, name: &str) -> Result<(), String> {
+		let name = name.trim();
+		if name == self.active_session {
+			return Ok(());
+		}
+		let chat_history = self
+			.sessions
+			.remove(name)
+			.ok_or_else(|| format!("There is no session named {}.", name))?;
+		let previous = std::mem::replace(&mut self.chat_history, chat_history);
+		let previous_name = std::mem::replace(&mut self.active_session, name.to_string());
+		self.sessions.insert(previous_name, previous);
+		self.recount_tokens_used();
+		Ok(())
+	}
 }
 
 
@@ -361,10 +887,15 @@ impl UserChannelData {
 /// ### Fields
 /// * `Gpt3_5` - the GPT-3.5 model
 /// * `Gpt4` - the GPT-4 model
+/// * `Other` - any other chat model of the API, by its name
 /// 
 /// 
 /// ### Methods
 /// * `get_token_limit` - returns the token limit of the model
+/// * `supports_json_mode` - returns whether the model can be asked to reply with a JSON object
+/// * `supports_vision` - returns whether the model can look at images in the prompt
+/// * `get_pricing` - returns the price of the model, see `MODEL_PRICES`
+/// * `from_name` - returns the model with the given API name
 /// 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Model {
@@ -373,28 +904,130 @@ pub enum Model {
 		token_limit: u32,
 	},
 	Gpt4,
+	Other(String),
 }
 // todo: add the token limit 
-impl Model {
-	pub fn default() -> Self {
+impl Default for Model {
+	fn default() -> Self {
 		Self::Gpt3_5 {
 			name: "gpt-3.5-turbo".to_string(),
 			token_limit: 4096,
 		}
 	}
+}
+impl Model {
 	pub fn get_name(&self) -> String {
 		match self {
 			Model::Gpt3_5 { name, .. } => name.clone(),
-			Model::Gpt4 => "GPT-4".to_string(),
+			// the name is sent to the API, which only knows it in lowercase
+			Model::Gpt4 => "gpt-4".to_string(),
+			Model::Other(name) => name.clone(),
 		}
 	}
 	pub fn get_token_limit(&self) -> &u32 {
 		match self {
 			Model::Gpt3_5 { token_limit, .. } => token_limit,
 			Model::Gpt4 => &8000,
+			// the context windows of the newer models, guessed from their names
+			Model::Other(name) if name.contains("32k") => &32_768,
+			Model::Other(name) if name.contains("16k") => &16_385,
+			Model::Other(name) if name.starts_with("gpt-4-") || name.starts_with("gpt-4o") || name.starts_with('o') => &128_000,
+			Model::Other(_) => &8000,
+		}
+	}
+	pub fn supports_json_mode(&self) -> bool {
+		match self {
+			// the snapshots from before November 2023 don't accept a response format
+			Model::Gpt3_5 { name, .. } => !name.ends_with("-0301") && !name.ends_with("-0613"),
+			Model::Gpt4 => false,
+			Model::Other(name) => {
+				!name.ends_with("-0314") && !name.ends_with("-0613") && !name.starts_with("gpt-4-32k")
+			}
 		}
 	}
+	pub fn supports_vision(&self) -> bool {
+		match self {
+			Model::Other(name) => {
+				let vision_family = ["gpt-4o", "chatgpt-4o", "gpt-4-turbo", "gpt-4.", "gpt-5", "o1", "o3", "o4"]
+					.iter()
+					.any(|family| name.starts_with(family));
+				// the small reasoning models only read text
+				vision_family && !name.starts_with("o1-mini") && !name.starts_with("o3-mini")
+			}
+			_ => false,
+		}
+	}
+	pub fn from_name(name: &str) -> Self {
+		let name = name.to_lowercase();
+		match name.as_str() {
+			"gpt-4" => Model::Gpt4,
+			_ if name.starts_with("gpt-3.5") => Model::Gpt3_5 {
+				name,
+				token_limit: *Model::default().get_token_limit(),
+			},
+			_ => Model::Other(name),
+		}
+	}
+	pub fn get_pricing(&self) -> &'static ModelPricing {
+		match self {
+			Model::Gpt3_5 { .. } => &MODEL_PRICES.gpt3_5,
+			Model::Gpt4 => &MODEL_PRICES.gpt4,
+			// models without a listed price are estimated like the family they come from
+			Model::Other(name) if name.starts_with("gpt-3.5") => &MODEL_PRICES.gpt3_5,
+			Model::Other(_) => &MODEL_PRICES.gpt4,
+		}
+	}
+
+}
+
+/// The models suggested by `/model` until the models of the API key are known
+pub const DEFAULT_CHAT_MODELS: [&str; 2] = ["gpt-3.5-turbo", "gpt-4"];
+
+/// Returns whether a model of the models endpoint can chat, which rules out the embeddings, image,
+/// audio and completion models.
+pub fn is_chat_model(name: &str) -> bool {
+	let chat_family = name.starts_with("gpt-")
+		|| name.starts_with("chatgpt-")
+		|| (name.starts_with('o') && name[1..].starts_with(|c: char| c.is_ascii_digit()));
+	let other_modality = ["instruct", "audio", "realtime", "tts", "transcribe", "image", "search"]
+		.iter()
+		.any(|kind| name.contains(kind));
+	chat_family && !other_modality
+}
+
+/// The price of a model in USD per 1K tokens
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelPricing {
+	pub prompt_per_1k: f64,
+	pub completion_per_1k: f64,
+}
 
+/// The prices of every model, see `Model::get_pricing`
+pub struct ModelPrices {
+	pub gpt3_5: ModelPricing,
+	pub gpt4: ModelPricing,
+}
+
+/// The list prices of the OpenAI API, update them here when they change
+pub const MODEL_PRICES: ModelPrices = ModelPrices {
+	gpt3_5: ModelPricing {
+		prompt_per_1k: 0.0005,
+		completion_per_1k: 0.0015,
+	},
+	gpt4: ModelPricing {
+		prompt_per_1k: 0.03,
+		completion_per_1k: 0.06,
+	},
+};
+
+/// Estimates the cost of a response in USD from its token usage and the model's list price
+///
+/// The prices are list prices, so discounts, free tiers and price changes aren't reflected.
+pub fn estimate_cost(usage: &UsageStruct, model: &Model) -> f64 {
+	let pricing = model.get_pricing();
+	(usage.prompt_tokens as f64 * pricing.prompt_per_1k
+		+ usage.completion_tokens as f64 * pricing.completion_per_1k)
+		/ 1000.0
 }
 
 /// # Personality
@@ -404,19 +1037,29 @@ impl Model {
 /// ### Fields
 /// * `name` - the name of the personality
 /// * `prompt` - the prompt that is sent to the model
-/// * `tokens` - the amount of tokens that the personality uses
+/// * `tokens` - the estimated amount of tokens of the prompt, see `estimate_tokens`
+/// * `description` - a short description of the personality shown to users
+/// * `display_name` - the name replies are posted with through a webhook, the bot's name when unset
+/// * `avatar_url` - the avatar replies are posted with through a webhook, the bot's avatar when unset
+/// * `max_context_entries` - how many exchanges of the chat history are sent with a prompt, the user's `history` setting when unset
 /// 
 /// 
 /// ### Methods
-/// * `new` - creates a new Personality struct
+/// * `new` - creates a new Personality struct and estimates the tokens of the prompt
 /// * `default` - returns the default personality
+/// * `set_prompt` - replaces the prompt and estimates its tokens again
+/// * `webhook_identity` - returns the name and avatar to post webhook replies with
 /// 
 /// 
 /// # Usage
 /// ```
 /// use crate::user::Personality;
 /// 
-/// let personality = Personality::new("default".to_string(), "You are a helpful assistant.".to_string(), 0);
+/// let personality = Personality::new(
+///     "default".to_string(),
+///     "You are a helpful assistant.".to_string(),
+///     "A simple assistant".to_string(),
+/// );
 /// ```
 /// 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -425,25 +1068,86 @@ pub struct Personality {
 	pub prompt: String,
 	pub tokens: u64,
 	pub description: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub display_name: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub avatar_url: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub max_context_entries: Option<usize>,
+}
+impl Default for Personality {
+	fn default() -> Self {
+		Self::new(
+			"default".to_string(),
+			"You are a helpful assistant.".to_string(),
+			"A simple assistant".to_string(),
+		)
+	}
 }
 impl Personality {
-	pub fn new(name: String, prompt: String, tokens: u64, description: String) -> Self {
+	pub fn new(name: String, prompt: String, description: String) -> Self {
 		Self {
 			name,
+			tokens: estimate_tokens(&prompt),
 			prompt,
-			tokens,
 			description,
+			display_name: None,
+			avatar_url: None,
+			max_context_entries: None,
 		}
 	}
-	pub fn default() -> Self {
-		Self {
-			name: "default".to_string(),
-			prompt: "You are a helpful assistant.".to_string(),
-			tokens: 0,
-			description: "No description".to_string(),
+	pub fn set_prompt(&mut self, prompt: String) {
+		self.tokens = estimate_tokens(&prompt);
+		self.prompt = prompt;
+	}
+	/// Returns the name and avatar URL to post webhook replies with, falling back to the given
+	/// identity of the bot for the ones that aren't set.
+	pub fn webhook_identity(&self, bot_name: &str, bot_avatar_url: &str) -> (String, String) {
+		(
+			self.display_name.clone().unwrap_or_else(|| bot_name.to_string()),
+			self.avatar_url.clone().unwrap_or_else(|| bot_avatar_url.to_string()),
+		)
+	}
+}
+
+/// # ResponseLength
+/// the length of the replies a user asks for, added to the system prompt of any personality
+/// 
+/// 
+/// ### Fields
+/// * `Concise` - short and to the point replies, set with `/concise`
+/// * `Verbose` - detailed replies, set with `/verbose`
+/// 
+/// 
+/// ### Methods
+/// * `instruction` - returns the instruction added to the system prompt
+/// * `name` - returns the name of the command that sets it
+/// 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResponseLength {
+	Concise,
+	Verbose,
+}
+impl ResponseLength {
+	pub fn instruction(&self) -> &'static str {
+		match self {
+			ResponseLength::Concise => "Keep your replies short and to the point, a few sentences at most.",
+			ResponseLength::Verbose => "Give detailed and thorough replies, explaining your reasoning with examples.",
 		}
 	}
-	
+	pub fn name(&self) -> &'static str {
+		match self {
+			ResponseLength::Concise => "concise",
+			ResponseLength::Verbose => "verbose",
+		}
+	}
+}
+
+/// Estimates the amount of tokens of a text
+///
+/// There is no tokenizer yet, so this uses the rule of thumb of about 4 characters per token for English text.
+pub fn estimate_tokens(text: &str) -> u64 {
+	(text.chars().count() as u64).div_ceil(4)
 }
 
 /// # CommandState
@@ -459,3 +1163,371 @@ pub enum CommandState {
 	None,
 	PersonalityCommandState(String),
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn set_by_key_validates_the_values() {
+		let mut settings = UserSettings::new();
+		let personas = vec![Personality::new("pirate".to_string(), "Arr.".to_string(), String::new())];
+		assert!(settings.set_by_key("privacy", "private", &personas).is_ok());
+		assert!(settings.get_chat_privacy());
+		assert!(settings.set_by_key("temperature", "1.5", &personas).is_ok());
+		assert_eq!(settings.get_temperature(), 1.5);
+		assert!(settings.set_by_key("personality", "pirate", &personas).is_ok());
+		assert_eq!(settings.get_personality().name, "pirate");
+		assert!(settings.set_by_key("show_tokens", "yes", &personas).is_ok());
+		assert!(settings.get_show_token_footer());
+		assert!(settings.set_by_key("seed", "42", &personas).is_ok());
+		assert_eq!(settings.get_seed(), Some(42));
+		assert!(settings.set_by_key("seed", "-1", &personas).is_err());
+		assert!(settings.set_by_key("seed", "clear", &personas).is_ok());
+		assert_eq!(settings.get_seed(), None);
+		assert!(settings.set_by_key("template", "Answer briefly: {msg}", &personas).is_ok());
+		assert_eq!(settings.apply_prompt_template("why?"), "Answer briefly: why?");
+		assert!(settings.set_by_key("template", "Answer briefly", &personas).is_err());
+		assert!(settings.set_by_key("template", "{msg} {msg}", &personas).is_err());
+		assert!(settings.set_by_key("template", "clear", &personas).is_ok());
+		assert_eq!(settings.apply_prompt_template("why?"), "why?");
+
+		assert!(settings.set_by_key("temperature", "3", &personas).is_err());
+		assert!(settings.set_by_key("choices", "many", &personas).is_err());
+		assert!(settings.set_by_key("personality", "ninja", &personas).is_err());
+		assert!(settings.set_by_key("model", "gpt-4", &personas).is_err());
+		assert_eq!(settings.get_temperature(), 1.5);
+	}
+
+	fn entry(total_tokens: u32) -> UserChatHistoryEntry {
+		UserChatHistoryEntry::new(
+			String::new(),
+			"hello".to_string(),
+			"hi".to_string(),
+			total_tokens,
+			0,
+			0,
+		)
+	}
+
+	#[test]
+	fn adding_entries_accumulates_tokens() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		channel_data.add_chat_history_entry(entry(10));
+		channel_data.add_chat_history_entry(entry(15));
+
+		assert_eq!(*channel_data.get_tokens_used(), 25);
+		assert_eq!(channel_data.chat_history.len(), 2);
+	}
+
+	#[test]
+	fn sessions_keep_their_own_history() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		channel_data.add_chat_history_entry(entry(10));
+
+		assert!(channel_data.new_session("work").is_ok());
+		assert_eq!(channel_data.get_active_session(), "work");
+		assert!(channel_data.chat_history.is_empty());
+		assert_eq!(*channel_data.get_tokens_used(), 0);
+		channel_data.add_chat_history_entry(entry(15));
+		channel_data.add_chat_history_entry(entry(5));
+
+		assert!(channel_data.new_session("work").is_err());
+		assert!(channel_data.switch_session("missing").is_err());
+		assert!(channel_data.switch_session(DEFAULT_SESSION).is_ok());
+		assert_eq!(channel_data.chat_history.len(), 1);
+		assert_eq!(*channel_data.get_tokens_used(), 10);
+		assert_eq!(channel_data.session_names(), vec!["default".to_string(), "work".to_string()]);
+
+		assert!(channel_data.switch_session("work").is_ok());
+		assert_eq!(*channel_data.get_tokens_used(), 20);
+	}
+
+	#[test]
+	fn clearing_the_history_of_a_user_keeps_the_others() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		for (author, tokens) in [(1, 10), (2, 15), (1, 5)] {
+			let mut entry = entry(tokens);
+			entry.author = Some(UserId(author));
+			channel_data.add_chat_history_entry(entry);
+		}
+
+		channel_data.clear_chat_history_of(UserId(1));
+		assert_eq!(channel_data.chat_history.len(), 1);
+		assert_eq!(channel_data.chat_history[0].author, Some(UserId(2)));
+		assert_eq!(*channel_data.get_tokens_used(), 15);
+	}
+
+	#[test]
+	fn channel_data_from_before_sessions_is_the_default_session() {
+		let stored = json!({
+			"channel_id": "1",
+			"tokens_used": 0,
+			"chat_history": [],
+			"system_prompt": null
+		});
+		let channel_data: UserChannelData = serde_json::from_value(stored).unwrap();
+		assert_eq!(channel_data.get_active_session(), DEFAULT_SESSION);
+		assert!(channel_data.sessions.is_empty());
+	}
+
+	#[test]
+	fn removing_oldest_entry_decrements_tokens() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		channel_data.add_chat_history_entry(entry(10));
+		channel_data.add_chat_history_entry(entry(15));
+
+		let removed = channel_data.remove_oldest_entry();
+
+		assert_eq!(removed.map(|e| e.total_tokens), Some(10));
+		assert_eq!(*channel_data.get_tokens_used(), 15);
+		assert_eq!(channel_data.chat_history.len(), 1);
+	}
+
+	#[test]
+	fn removing_an_entry_by_timestamp_decrements_tokens() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		for tokens in [10, 20, 30] {
+			channel_data.add_chat_history_entry(entry(tokens));
+		}
+		let timestamp = Utc::now() + chrono::Duration::seconds(1);
+		channel_data.chat_history[1].timestamp = timestamp;
+
+		let removed = channel_data.remove_entry_at(timestamp);
+
+		assert_eq!(removed.map(|e| e.total_tokens), Some(20));
+		assert_eq!(*channel_data.get_tokens_used(), 40);
+		assert_eq!(channel_data.remove_entry_at(timestamp), None);
+	}
+
+	#[test]
+	fn removing_from_empty_history_does_not_panic() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+
+		assert_eq!(channel_data.remove_oldest_entry(), None);
+		assert_eq!(*channel_data.get_tokens_used(), 0);
+	}
+
+	#[test]
+	fn repeated_removal_gets_under_limit() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		for tokens in [10, 20, 30, 40] {
+			channel_data.add_chat_history_entry(entry(tokens));
+		}
+
+		while *channel_data.get_tokens_used() > 75 {
+			channel_data.remove_oldest_entry();
+		}
+
+		assert_eq!(*channel_data.get_tokens_used(), 70);
+		assert_eq!(channel_data.chat_history.len(), 2);
+	}
+
+	#[test]
+	fn trimming_removes_entries_until_under_limit() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		for tokens in [10, 20, 30, 40] {
+			channel_data.add_chat_history_entry(entry(tokens));
+		}
+
+		channel_data.trim_to_token_limit(75);
+
+		assert_eq!(*channel_data.get_tokens_used(), 70);
+		assert_eq!(channel_data.chat_history.len(), 2);
+	}
+
+	#[test]
+	fn trimming_an_oversized_entry_empties_history() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		channel_data.add_chat_history_entry(entry(100));
+
+		channel_data.trim_to_token_limit(50);
+
+		assert_eq!(*channel_data.get_tokens_used(), 0);
+		assert!(channel_data.chat_history.is_empty());
+	}
+
+	#[test]
+	fn trimming_to_entry_limit_keeps_the_latest_entries() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		for tokens in [10, 20, 30] {
+			channel_data.add_chat_history_entry(entry(tokens));
+		}
+
+		channel_data.trim_to_entry_limit(2);
+
+		assert_eq!(channel_data.chat_history.len(), 2);
+		assert_eq!(*channel_data.get_tokens_used(), 50);
+
+		channel_data.trim_to_entry_limit(0);
+		assert_eq!(channel_data.chat_history.len(), 2);
+	}
+
+	#[test]
+	fn continuing_extends_the_latest_entry() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		channel_data.add_chat_history_entry(entry(10));
+		channel_data.chat_history[0].truncated = true;
+
+		channel_data.continue_latest_entry(" there", 3, 8, false);
+
+		let latest = &channel_data.chat_history[0];
+		assert_eq!(latest.ai_message, "hi there");
+		assert_eq!((latest.total_tokens, latest.completion_tokens), (18, 3));
+		assert!(!latest.truncated);
+		assert_eq!(*channel_data.get_tokens_used(), 18);
+	}
+
+	#[test]
+	fn tokens_used_matches_the_remaining_entries() {
+		let mut channel_data = UserChannelData::new(ChannelId(1));
+		let remaining = |channel_data: &UserChannelData| -> u32 {
+			channel_data.chat_history.iter().map(|entry| entry.total_tokens).sum()
+		};
+		for tokens in [10, 20, 30, 40, 50] {
+			channel_data.add_chat_history_entry(entry(tokens));
+			assert_eq!(*channel_data.get_tokens_used(), remaining(&channel_data));
+		}
+
+		channel_data.trim_to_token_limit(130);
+		assert_eq!(*channel_data.get_tokens_used(), remaining(&channel_data));
+		channel_data.trim_to_entry_limit(2);
+		assert_eq!(*channel_data.get_tokens_used(), remaining(&channel_data));
+		channel_data.remove_latest_entry();
+		assert_eq!(*channel_data.get_tokens_used(), remaining(&channel_data));
+		assert_eq!(*channel_data.get_tokens_used(), 40);
+	}
+
+	#[test]
+	fn the_least_recently_used_channels_are_dropped() {
+		let mut usage = UserUsage::new();
+		let start = Utc::now();
+		for id in 1..=4 {
+			let mut channel_data = UserChannelData::new(ChannelId(id));
+			channel_data.last_used = start + chrono::Duration::minutes(id as i64);
+			usage.channel_history.insert(ChannelId(id), channel_data);
+		}
+		usage.channel_history.get_mut(&ChannelId(1)).unwrap().last_used = start + chrono::Duration::minutes(10);
+
+		usage.trim_channels(0);
+		assert_eq!(usage.channel_history.len(), 4);
+		usage.trim_channels(2);
+		let mut kept: Vec<u64> = usage.channel_history.keys().map(|channel_id| channel_id.0).collect();
+		kept.sort();
+		assert_eq!(kept, [1, 4]);
+	}
+
+	#[test]
+	fn summary_json_counts_the_chat_history() {
+		let mut user = User::new(UserId(1));
+		let mut channel_data = UserChannelData::new(ChannelId(2));
+		channel_data.add_chat_history_entry(entry(10));
+		channel_data.add_chat_history_entry(entry(15));
+		user.usage.channel_history.insert(ChannelId(2), channel_data);
+		user.usage.add_total_tokens(25);
+
+		let summary = user.to_summary_json();
+
+		let channel = &summary["usage"]["channel_history"]["2"];
+		assert_eq!(channel["chat_history"], json!({ "entries": 2 }));
+		assert_eq!(channel["tokens_used"], 25);
+		assert_eq!(summary["usage"]["total_tokens"], 25);
+		assert_eq!(summary["settings"]["chat_privacy"], user.settings.chat_privacy);
+	}
+
+	#[test]
+	fn cost_is_estimated_from_the_model_prices() {
+		let usage = UsageStruct {
+			prompt_tokens: 2000,
+			completion_tokens: 1000,
+			total_tokens: 3000,
+		};
+		assert!((estimate_cost(&usage, &Model::default()) - 0.0025).abs() < 1e-9);
+		assert!((estimate_cost(&usage, &Model::Gpt4) - 0.12).abs() < 1e-9);
+
+		let mut user_usage = UserUsage::new();
+		user_usage.add_cost(&usage, &Model::Gpt4);
+		user_usage.add_cost(&usage, &Model::default());
+		assert!((user_usage.get_estimated_cost() - 0.1225).abs() < 1e-9);
+	}
+
+	#[test]
+	fn models_are_parsed_from_their_api_names() {
+		assert_eq!(Model::from_name("GPT-4"), Model::Gpt4);
+		assert_eq!(Model::from_name("gpt-3.5-turbo"), Model::default());
+		let model = Model::from_name("gpt-4o-mini");
+		assert_eq!(model, Model::Other("gpt-4o-mini".to_string()));
+		assert_eq!(model.get_name(), "gpt-4o-mini");
+		assert_eq!(*model.get_token_limit(), 128_000);
+		assert!(model.supports_json_mode());
+		assert!(model.supports_vision());
+		assert!(!Model::Gpt4.supports_vision());
+		assert!(!Model::from_name("o1-mini").supports_vision());
+
+		assert!(is_chat_model("gpt-4o"));
+		assert!(is_chat_model("o1-mini"));
+		assert!(!is_chat_model("gpt-3.5-turbo-instruct"));
+		assert!(!is_chat_model("text-embedding-3-small"));
+		assert!(!is_chat_model("omni-moderation-latest"));
+		assert!(!is_chat_model("dall-e-3"));
+	}
+
+	#[test]
+	fn json_mode_needs_a_supporting_model() {
+		let mut settings = UserSettings::new();
+		assert!(settings.set_json_mode(true).is_ok());
+		assert!(settings.get_json_mode());
+
+		settings.model = Model::Gpt3_5 {
+			name: "gpt-3.5-turbo-0613".to_string(),
+			token_limit: 4096,
+		};
+		settings.json_mode = false;
+		assert!(settings.set_json_mode(true).is_err());
+		assert!(!settings.get_json_mode());
+		assert!(settings.set_json_mode(false).is_ok());
+	}
+
+	#[test]
+	fn sampling_settings_reject_out_of_range_values() {
+		let mut settings = UserSettings::new();
+
+		assert!(settings.set_temperature(0.8).is_ok());
+		assert!(settings.set_temperature(2.5).is_err());
+		assert!(settings.set_top_p(-0.1).is_err());
+		assert!(settings.set_presence_penalty(-2.0).is_ok());
+		assert!(settings.set_frequency_penalty(2.1).is_err());
+		assert_eq!(settings.get_temperature(), 0.8);
+		assert_eq!(settings.get_top_p(), 1.0);
+		assert_eq!(settings.get_presence_penalty(), -2.0);
+		assert_eq!(settings.get_frequency_penalty(), 0.0);
+	}
+
+	#[test]
+	fn long_prompt_has_tokens() {
+		let personality = Personality::new(
+			"pirate".to_string(),
+			"You are a pirate and answer every question like a pirate would.".repeat(10),
+			"Talks like a pirate".to_string(),
+		);
+
+		assert!(personality.tokens > 100);
+	}
+
+	#[test]
+	fn webhook_identity_falls_back_to_the_bot() {
+		let mut personality: Personality =
+			serde_json::from_str(r#"{"name": "pirate", "prompt": "Arr.", "tokens": 0, "description": ""}"#).unwrap();
+		assert_eq!(
+			personality.webhook_identity("Bot", "https://example.com/bot.png"),
+			("Bot".to_string(), "https://example.com/bot.png".to_string())
+		);
+
+		personality.display_name = Some("Pirate Bot".to_string());
+		personality.avatar_url = Some("https://example.com/pirate.png".to_string());
+		assert_eq!(
+			personality.webhook_identity("Bot", "https://example.com/bot.png"),
+			("Pirate Bot".to_string(), "https://example.com/pirate.png".to_string())
+		);
+	}
+}